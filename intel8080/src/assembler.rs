@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+// This struct represents a tiny assembler used by the tests to write programs with labels:
+// the jumps and calls to a label get its absolute address when the program is built, e.g.
+//     Assembler::new(0x0100).op(&[0x06, 0x05]).label("loop").op(&[0x05]).jnz("loop").build()
+// The instructions without a label are written as bytes with op()
+pub struct Assembler {
+    origin: u16,
+    bytes: Vec<u8>,
+    labels: HashMap<&'static str, u16>,
+    //(offset of the address inside bytes, label it points to)
+    fixups: Vec<(usize, &'static str)>,
+}
+
+impl Assembler {
+    //origin is the address where the program will be loaded
+    pub fn new(origin: u16) -> Self {
+        Self {
+            origin,
+            bytes: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    //Gives name to the address of the next instruction, panics if name is already used
+    pub fn label(mut self, name: &'static str) -> Self {
+        let addr = self.origin + self.bytes.len() as u16;
+        assert!(self.labels.insert(name, addr).is_none(), "label {} defined twice", name);
+        self
+    }
+
+    //Appends an instruction written as its opcode followed by the operands
+    pub fn op(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    //Appends opcode followed by the address of label, which can be defined later
+    pub fn op_to(mut self, opcode: u8, label: &'static str) -> Self {
+        self.bytes.push(opcode);
+        self.fixups.push((self.bytes.len(), label));
+        self.bytes.extend_from_slice(&[0x00, 0x00]);
+        self
+    }
+
+    pub fn jmp(self, label: &'static str) -> Self {
+        self.op_to(0xc3, label)
+    }
+
+    pub fn jnz(self, label: &'static str) -> Self {
+        self.op_to(0xc2, label)
+    }
+
+    pub fn call(self, label: &'static str) -> Self {
+        self.op_to(0xcd, label)
+    }
+
+    //Returns the program with the addresses of the labels filled in,
+    //panics if a label is used but never defined
+    pub fn build(&self) -> Vec<u8> {
+        let mut bytes = self.bytes.clone();
+        for &(offset, label) in self.fixups.iter() {
+            let addr = match self.labels.get(label) {
+                Some(&addr) => addr,
+                None => panic!("label {} is not defined", label),
+            };
+            bytes[offset..offset + 2].copy_from_slice(&addr.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_are_resolved_forward_and_backward() {
+        let program = Assembler::new(0x0100)
+            .label("start")
+            .call("sub")
+            .jmp("start")
+            .label("sub")
+            .op(&[0x3e, 0x05])
+            .jnz("sub")
+            .op(&[0xc9])
+            .build();
+        assert_eq!(
+            program,
+            [0xcd, 0x06, 0x01, 0xc3, 0x00, 0x01, 0x3e, 0x05, 0xc2, 0x06, 0x01, 0xc9]
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn undefined_label_is_rejected() {
+        Assembler::new(0x0000).jmp("nowhere").build();
+    }
+}
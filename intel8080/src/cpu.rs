@@ -1,22 +1,198 @@
+#[cfg(feature = "log")]
 extern crate log;
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
 use std::mem;
-use log::{debug};
+use std::ops::RangeInclusive;
+use std::time::Instant;
+#[cfg(feature = "log")]
+use log::{debug, trace, log_enabled, Level};
 
 use super::bit;
+use super::flags::PARITY;
 use super::register::Register;
 use super::register::Flags;
-use super::memory::Memory;
-use super::disassembler::get_mnemonic;
+use super::memory::{Linear, Memory};
+use super::io::{IoBus, NullBus};
+use super::instruction::Reg;
+use super::invaders::{SpaceInvadersTiming, VIDEO_RAM};
+use super::disassembler::{get_mnemonic, get_length, get_cycles, decode, disassemble_backward, opcode_is_restricted};
 
+//Function called before an instruction is executed, see Cpu::set_pre_exec
+type PreExec = dyn FnMut(&mut Cpu, u8) -> bool;
+
+//Function that replaces the fetched opcodes, see Cpu::set_opcode_rewriter
+type OpcodeRewriter = dyn FnMut(u16, u8) -> u8;
+
+//Function called when the SP changes, see Cpu::set_sp_hook
+type SpHook = dyn FnMut(u16, u16);
+
+// This enum represents something noteworthy that happened while the cpu was running,
+// the events are collected by the cpu, see Cpu::events
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    //A restricted opcode was skipped because the cpu is in sandbox mode
+    Restricted { opcode: u8 },
+    //An instruction was fetched from the stack region, see Cpu::set_stack_region
+    ExecFromStack { addr: u16 },
+}
+
+// This enum represents why the cpu stopped running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunOutcome {
+    //The run reached the point it was waiting for
+    Completed,
+    //The cpu executed a HLT
+    Halted,
+    //The maximum number of instructions was executed
+    StepLimit,
+    //The limit set by Cpu::set_instruction_limit was reached
+    InstructionLimit,
+}
+
+// This enum represents why Cpu::step_debug stopped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DebugHit {
+    //The PC reached a breakpoint
+    Pc(u16),
+    //The instruction wrote to a watchpoint
+    Write(u16),
+}
+
+// This struct represents a single instruction executed by Cpu::step_verbose
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerboseStep {
+    pub addr: u16,
+    pub mnemonic: &'static str,
+    pub cycles: u8,
+    //(name, value before, value after) of every register changed by the instruction
+    pub changes: Vec<(&'static str, u16, u16)>,
+}
+
+// This struct represents a single instruction executed by Cpu::step_traced
+#[derive(Clone)]
+pub struct StepTrace {
+    pub pc: u16,
+    //The instruction with its operands, e.g. "MVI A,$05"
+    pub mnemonic: String,
+    //The opcode followed by its operands
+    pub bytes: Vec<u8>,
+    //The registers after the instruction was executed
+    pub register: Register,
+}
+
+// This struct represents a save state of the cpu and of its memory, see Cpu::snapshot
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub register: Register,
+    pub stop: bool,
+    pub interrupt: bool,
+    pub ei_delay: bool,
+    pub self_jump: bool,
+    //The bytes of an interrupt_with_opcode not fetched yet
+    pub injected: Vec<u8>,
+    //The RST numbers run_frame is waiting to deliver
+    pub deferred_interrupts: Vec<u8>,
+    pub total_cycles: u64,
+    //See Memory::save_state
+    pub memory: Vec<u8>,
+    //See IoBus::save_state
+    pub io: Vec<u8>,
+}
+
+// This struct represents the addresses used by Cpu::run_cpm_test_with, the default
+// ones are the CP/M warm boot (0x0000) and the BDOS entry point (0x0005)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CpmConfig {
+    //The program ends when it jumps here
+    pub exit: u16,
+    //The CALLs to this address are served by the BDOS stub
+    pub bdos: u16,
+}
+
+impl Default for CpmConfig {
+    fn default() -> Self {
+        Self {
+            exit: 0x0000,
+            bdos: 0x0005,
+        }
+    }
+}
+
+// This struct represents an operation that gave a wrong result in Cpu::self_test
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestError {
+    pub operation: &'static str,
+    //(A, F) after the operation
+    pub expected: (u8, u8),
+    pub found: (u8, u8),
+}
+
+//An operation checked by Cpu::self_test:
+//(name, A, B, carry before the operation, opcode, expected A, expected F)
+type SelfTestCase = (&'static str, u8, u8, bool, u8, u8, u8);
+
+//Operations checked by Cpu::self_test
+const SELF_TEST: [SelfTestCase; 15] = [
+    ("ADD", 0x3a, 0xc6, false, 0x80, 0x00, 0x57),
+    ("ADC", 0x42, 0x3d, true, 0x88, 0x80, 0x92),
+    ("SUB", 0x3e, 0x3e, false, 0x90, 0x00, 0x56),
+    ("SBB", 0x04, 0x02, true, 0x98, 0x01, 0x12),
+    ("ANA", 0xfc, 0x0f, false, 0xa0, 0x0c, 0x16),
+    ("XRA", 0x5c, 0x78, true, 0xa8, 0x24, 0x06),
+    ("ORA", 0x33, 0x0f, true, 0xb0, 0x3f, 0x06),
+    ("CMP", 0x0a, 0x05, true, 0xb8, 0x0a, 0x16),
+    ("RLC", 0xf2, 0x00, false, 0x07, 0xe5, 0x03),
+    ("RRC", 0xf2, 0x00, true, 0x0f, 0x79, 0x02),
+    ("RAL", 0xb5, 0x00, false, 0x17, 0x6a, 0x03),
+    ("RAR", 0x6a, 0x00, true, 0x1f, 0xb5, 0x02),
+    ("DAA", 0x9b, 0x00, false, 0x27, 0x01, 0x13),
+    ("INR", 0xff, 0x00, true, 0x3c, 0x00, 0x57),
+    ("DCR", 0x00, 0x00, false, 0x3d, 0xff, 0x86),
+];
 
 pub struct Cpu {
     pub register: Register,
     pub memory: Rc<RefCell<dyn Memory>>,
+    pub io: Rc<RefCell<dyn IoBus>>,
     pub stop: bool,
-    pub interrupt: bool
+    pub interrupt: bool,
+    ring_trace: Option<VecDeque<String>>,
+    ring_trace_capacity: usize,
+    reset_vector: u16,
+    branch_profile: Option<BTreeMap<u16, (u64, u64)>>,
+    self_jump: bool,
+    pre_exec: Option<Box<PreExec>>,
+    sandbox: bool,
+    events: Vec<Event>,
+    last_cycles: u8,
+    total_cycles: u64,
+    ei_delay: bool,
+    opcode_rewriter: Option<Box<OpcodeRewriter>>,
+    stack_region: Option<RangeInclusive<u16>>,
+    cycle_attribution: Option<BTreeMap<u16, u64>>,
+    injected: VecDeque<u8>,
+    deferred_interrupts: VecDeque<u8>,
+    sp_hook: Option<Box<SpHook>>,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: BTreeSet<u16>,
+    watch_hits: Vec<u16>,
+    instruction_limit: Option<u64>,
+    instructions: u64
+}
+
+//Returns true for the conditional jumps, calls and returns
+fn is_conditional_branch(opcode: u8) -> bool {
+    matches!(opcode & 0xc7, 0xc0 | 0xc2 | 0xc4)
+}
+
+//Returns true for the instructions that push the PC before jumping: CALL, Call condition and RST
+fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xcd | 0xdd | 0xed | 0xfd) || matches!(opcode & 0xc7, 0xc4 | 0xc7)
 }
 
 //This impl block implements Arithmetic Group operations
@@ -32,7 +208,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (a & 0x0f) + (value & 0x0f) > 0x0f);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, u16::from(a) + u16::from(value) > 0xff);
         self.register.a = result;
     }
@@ -49,7 +225,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (a & 0x0f) + (value & 0x0f) + c > 0x0f);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, u16::from(a) + u16::from(value) + u16::from(c) > 0xff);
         self.register.a = result;
     }
@@ -65,7 +241,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (a as i8 & 0x0f) - (value as i8 & 0x0f) >= 0x00);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, u16::from(a) < u16::from(value));
         self.register.a = result;
     }
@@ -82,7 +258,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (a as i8 & 0x0f) - (value as i8 & 0x0f) - (c as i8) >= 0);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, u16::from(a) < u16::from(value) + u16::from(c));
         self.register.a = result;
     }
@@ -97,7 +273,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (value & 0x0f) + 0x01 > 0x0f);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         result
     }
 
@@ -111,7 +287,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, (result & 0x0f) != 0x0f);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         result
     }
 
@@ -122,7 +298,7 @@ impl Cpu {
     fn alu_dad(&mut self, value: u16) {
         let hl = self.register.get_hl();
         let result = hl.wrapping_add(value);
-        self.register.set_flag(Flags::Carry, hl > 0xffff - value);
+        self.register.set_flag(Flags::Carry, u32::from(hl) + u32::from(value) > 0xffff);
         self.register.set_hl(result);
     }
 
@@ -134,16 +310,16 @@ impl Cpu {
     fn alu_daa(&mut self) {
         let mut to_add: u8 = 0;
         let mut carry = self.register.get_flag(Flags::Carry);
-        let low = self.register.a & 0x0f;
-        let high = self.register.a >> 4;
-        
+
         //if 4 LSB have a value > 9 or the AC flag is set add 6 to the 4 LSB
-        if (low > 9) || self.register.get_flag(Flags::AC) {
-            to_add += 0x06; 
+        if (self.register.a & 0x0f) > 9 || self.register.get_flag(Flags::AC) {
+            to_add += 0x06;
         }
-        
-        //if 4 MSB have a value > 9 after the previous operation or the C flag is set add 6 to the 4 MSB
-        if (high > 9) || self.register.get_flag(Flags::Carry) || ((high >= 9) && (low > 9)) {
+
+        //if 4 MSB have a value > 9 after the previous operation or the C flag is set add 6 to the 4 MSB,
+        //the 4 MSB are checked after adding the correction of the 4 LSB because it can carry into them
+        let high = (u16::from(self.register.a) + u16::from(to_add)) >> 4;
+        if high > 9 || carry {
             to_add += 0x60;
             carry = true;       //there will be an overflow
         }
@@ -167,7 +343,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, ((a | value) & 0x08) != 0x00);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, false);
         self.register.a = result;
     }
@@ -184,7 +360,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, false);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, false);
         self.register.a = result;
     }
@@ -201,7 +377,7 @@ impl Cpu {
         self.register.set_flag(Flags::Zero, result == 0x00);
         self.register.set_flag(Flags::Sign, bit::get(result, 7));
         self.register.set_flag(Flags::AC, false);
-        self.register.set_flag(Flags::Parity, result.count_ones() & 0x01 == 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
         self.register.set_flag(Flags::Carry, false);
         self.register.a = result;
     }
@@ -211,10 +387,15 @@ impl Cpu {
     //  CMP register
     //  CMP memory
     //  CPI data
+    //A IS NOT MODIFIED
     fn alu_cmp(&mut self, value: u8) {
         let a = self.register.a;
-        self.alu_sub(value);
-        self.register.a = a;        
+        let result = a.wrapping_sub(value);
+        self.register.set_flag(Flags::Zero, result == 0x00);
+        self.register.set_flag(Flags::Sign, bit::get(result, 7));
+        self.register.set_flag(Flags::AC, (a as i8 & 0x0f) - (value as i8 & 0x0f) >= 0x00);
+        self.register.set_flag(Flags::Parity, PARITY[usize::from(result)]);
+        self.register.set_flag(Flags::Carry, u16::from(a) < u16::from(value));
     }
 
     //Rotate accumulator left: A(N+1) = A(N); A(0) = A(7)
@@ -294,6 +475,17 @@ impl Cpu {
 
 //This impl block implements Data Transfer Group operations
 impl Cpu {
+    //Move register or memory: dst = src
+    //Instructions:
+    // MOV register, register
+    // MOV memory, register
+    // MOV register, memory
+    //NO FLAGS ARE AFFECTED
+    fn alu_mov(&mut self, dst: Reg, src: Reg) {
+        let value = self.get_reg(src);
+        self.set_reg(dst, value);
+    }
+
     //Load Accumulator Direct: A = Memory[(byte3)(byte2)]
     //Instructions:
     // LDA
@@ -311,7 +503,7 @@ impl Cpu {
     fn alu_sta(&mut self) {
         let value = self.register.a;
         let idx = self.get_next_word();
-        self.memory.borrow_mut().set(usize::from(idx), value);
+        self.write_byte(idx, value);
     }
 
     //Load H and L direct: (HL) = Memory[(byte3)(byte2)]
@@ -331,24 +523,23 @@ impl Cpu {
     fn alu_shld(&mut self) {
         let index = self.get_next_word();
         let value = self.register.get_hl();
-        self.memory.borrow_mut().set_word(usize::from(index), value);
+        self.write_word(index, value);
     }
 
-    //Load accumulator indirec: A = Memory[rp] rp can be either HL or DE
+    //Load accumulator indirect: A = Memory[rp] rp can be either BC or DE
     //Instructions:
     // LDAX
     //NO FLAGS ARE AFFECTED
     fn alu_ldax(&mut self, index: u16) {
-        let index = index;
         self.register.a = self.memory.borrow().get(usize::from(index));
     }
 
-    //Store accumulator indirect: Memory[rp] = A rp can be either HL or DE
+    //Store accumulator indirect: Memory[rp] = A rp can be either BC or DE
     //Instructions:
     // STAX
     //NO FLAGS ARE AFFECTED
     fn alu_stax(&mut self, index: u16) {
-        self.memory.borrow_mut().set(usize::from(index), self.register.a);
+        self.write_byte(index, self.register.a);
     }
 
     //Exchange H and L with D and E: H = D, L = E, D = H, L = E
@@ -394,11 +585,16 @@ impl Cpu {
     // CP
     // CM
     //NO FLAGS ARE AFFECTED
-    fn alu_call(&mut self, condition: bool) {
+    //Returns the cycles used: 17 if the call is done, 11 if it isn't
+    fn alu_call(&mut self, condition: bool) -> u8 {
         let pos = self.get_next_word();
         if condition {
             self.stack_push(self.register.pc);
             self.register.pc = pos;
+            17
+        }
+        else {
+            11
         }
     }
 
@@ -414,9 +610,15 @@ impl Cpu {
     // RP
     // RM
     //NO FLAGS ARE AFFECTED
-    fn alu_ret(&mut self, condition: bool) {
+    //Returns the cycles used by a Return condition: 11 if the return is done, 5 if it isn't.
+    //RET doesn't check a condition and uses 10 cycles
+    fn alu_ret(&mut self, condition: bool) -> u8 {
         if condition {
             self.register.pc = self.stack_pop();
+            11
+        }
+        else {
+            5
         }
     }
 
@@ -444,31 +646,77 @@ impl Cpu {
     // XTHL
     //NO FLAGS ARE AFFECTED
     fn alu_xthl(&mut self) {
-        let mut mem = self.memory.borrow_mut();
-        let sp = mem.get_word(usize::from(self.register.sp));
+        let sp = self.memory.borrow().get_word(usize::from(self.register.sp));
         let hl = self.register.get_hl();
 
         self.register.set_hl(sp);
-        mem.set_word(usize::from(self.register.sp), hl);
+        self.write_word(self.register.sp, hl);
     }
 }
 
 //This impl block implements some utilities that allow to do some operations with stack and memory
 impl Cpu {
+    fn set_sp(&mut self, value: u16) {
+        let old = self.register.sp;
+        self.register.sp = value;
+        if let Some(sp_hook) = self.sp_hook.as_mut() {
+            sp_hook(old, value);
+        }
+    }
+
     fn stack_push(&mut self, value: u16) {
-        self.register.sp = self.register.sp.wrapping_sub(2);
-        self.memory.borrow_mut().set_word(usize::from(self.register.sp), value);
+        self.set_sp(self.register.sp.wrapping_sub(2));
+        self.write_word(self.register.sp, value);
     }
 
     fn stack_pop(&mut self) -> u16 {
         let result = self.memory.borrow().get_word(usize::from(self.register.sp));
-        self.register.sp = self.register.sp.wrapping_add(2);
+        self.set_sp(self.register.sp.wrapping_add(2));
         result
     }
 
-    fn set_m(&self, value: u8) {
-        let index = self.register.get_hl();
-        self.memory.borrow_mut().set(usize::from(index), value);
+    fn set_m(&mut self, value: u8) {
+        self.write_byte(self.register.get_hl(), value);
+    }
+
+    //The writes of the instructions go through write_byte to check the watchpoints
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.memory.borrow_mut().set(usize::from(addr), value);
+        if self.watchpoints.contains(&addr) {
+            self.watch_hits.push(addr);
+        }
+    }
+
+    fn write_word(&mut self, addr: u16, value: u16) {
+        self.write_byte(addr, (value & 0xff) as u8);
+        self.write_byte(addr.wrapping_add(1), (value >> 8) as u8);
+    }
+
+    //Reads the register encoded in the opcodes, Reg::M is the memory pointed by HL
+    fn get_reg(&self, reg: Reg) -> u8 {
+        match reg {
+            Reg::B => self.register.b,
+            Reg::C => self.register.c,
+            Reg::D => self.register.d,
+            Reg::E => self.register.e,
+            Reg::H => self.register.h,
+            Reg::L => self.register.l,
+            Reg::M => self.get_m(),
+            Reg::A => self.register.a,
+        }
+    }
+
+    fn set_reg(&mut self, reg: Reg, value: u8) {
+        match reg {
+            Reg::B => self.register.b = value,
+            Reg::C => self.register.c = value,
+            Reg::D => self.register.d = value,
+            Reg::E => self.register.e = value,
+            Reg::H => self.register.h = value,
+            Reg::L => self.register.l = value,
+            Reg::M => self.set_m(value),
+            Reg::A => self.register.a = value,
+        }
     }
 
     fn get_m(&self) -> u8 {
@@ -476,49 +724,196 @@ impl Cpu {
         self.memory.borrow().get(usize::from(index))
     }
 
+    //The bytes supplied by interrupt_with_opcode are fetched before the memory
     fn get_next_byte(&mut self) -> u8 {
+        if let Some(value) = self.injected.pop_front() {
+            return value;
+        }
         let value = self.memory.borrow().get(usize::from(self.register.pc));
-        self.register.pc += 1;
+        self.register.pc = self.register.pc.wrapping_add(1);
         value
     }
 
     fn get_next_word(&mut self) -> u16 {
-        let value = self.memory.borrow().get_word(usize::from(self.register.pc));
-        self.register.pc += 2;
-        value
+        u16::from(self.get_next_byte()) | (u16::from(self.get_next_byte()) << 8)
+    }
+
+    //Returns the opcode the next call to next() executes without fetching it
+    fn pending_opcode(&self) -> u8 {
+        match self.injected.front() {
+            Some(&opcode) => opcode,
+            None => self.memory.borrow().get(usize::from(self.register.pc)),
+        }
+    }
+
+    //Returns the bytes of the instruction the next call to next() executes, operands included
+    fn pending_bytes(&self) -> Vec<u8> {
+        if !self.injected.is_empty() {
+            return self.injected.iter().copied().collect();
+        }
+        let memory = self.memory.borrow();
+        let pc = self.register.pc;
+        let length = get_length(memory.get(usize::from(pc)));
+        (0..length).map(|idx| memory.get(usize::from(pc.wrapping_add(idx as u16)))).collect()
     }
 }
 
 // This impl block implements how the Cpu will be used and will handle the opcodes
 impl Cpu {
+    /// Returns a cpu that runs from address 0 of memory, with the I/O ports on a NullBus
+    ///
+    /// ```
+    /// use std::cell::RefCell;
+    /// use std::rc::Rc;
+    /// use intel8080::{Cpu, Linear, Memory};
+    ///
+    /// let memory = Rc::new(RefCell::new(Linear::new()));
+    /// //MVI A,$05; INR A
+    /// memory.borrow_mut().set(0x0000, 0x3e);
+    /// memory.borrow_mut().set(0x0001, 0x05);
+    /// memory.borrow_mut().set(0x0002, 0x3c);
+    ///
+    /// let mut cpu = Cpu::new(memory);
+    /// cpu.next();
+    /// cpu.next();
+    /// assert_eq!(cpu.register.a, 0x06);
+    /// assert_eq!(cpu.register.pc, 0x0003);
+    /// ```
     pub fn new(memory: Rc<RefCell<dyn Memory>>) -> Self {
         Self {
             register: Register::new(),
             memory,
+            io: Rc::new(RefCell::new(NullBus)),
             stop: false,
-            interrupt: false
+            interrupt: false,
+            ring_trace: None,
+            ring_trace_capacity: 0,
+            reset_vector: 0x0000,
+            branch_profile: None,
+            self_jump: false,
+            pre_exec: None,
+            sandbox: false,
+            events: Vec::new(),
+            last_cycles: 0,
+            total_cycles: 0,
+            ei_delay: false,
+            opcode_rewriter: None,
+            stack_region: None,
+            cycle_attribution: None,
+            injected: VecDeque::new(),
+            deferred_interrupts: VecDeque::new(),
+            sp_hook: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeSet::new(),
+            watch_hits: Vec::new(),
+            instruction_limit: None,
+            instructions: 0
         }
     }
 
-    pub fn next(&mut self) {
-        let opcode = self.get_next_byte();
+    //Restores the power-on state of the cpu: the registers are cleared, except for the
+    //fixed bit of F, the PC is moved to the reset vector and the cpu is running with the
+    //interrupts disabled. The memory, the devices and the debugging tools are not modified
+    pub fn reset(&mut self) {
+        self.register = Register::new();
+        self.register.pc = self.reset_vector;
+        self.stop = false;
+        self.interrupt = false;
+        self.ei_delay = false;
+        self.self_jump = false;
+        self.last_cycles = 0;
+        self.total_cycles = 0;
+        self.injected.clear();
+        self.deferred_interrupts.clear();
+    }
 
-        debug!(
-            "IN  {:04x} {} PC={:04x} SP={:04x} A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x}",
-            opcode,
-            get_mnemonic(opcode),
-            self.register.pc.wrapping_sub(1),
-            self.register.sp,
-            self.register.a,
-            self.register.f,
-            self.register.b,
-            self.register.c,
-            self.register.d,
-            self.register.e,
-            self.register.h,
-            self.register.l
+    //Copies program in memory starting from addr and moves the PC to addr,
+    //panics if the program doesn't fit in the 64KB address space
+    pub fn load_program(&mut self, addr: u16, program: &[u8]) {
+        assert!(
+            usize::from(addr) + program.len() <= 0x10000,
+            "the program doesn't fit in memory"
         );
 
+        let mut memory = self.memory.borrow_mut();
+        for (idx, &byte) in program.iter().enumerate() {
+            memory.set(usize::from(addr) + idx, byte);
+        }
+        self.register.pc = addr;
+    }
+
+    //Sets the address where reset() will move the PC, the i8080 always uses 0x0000
+    pub fn set_reset_vector(&mut self, addr: u16) {
+        self.reset_vector = addr;
+    }
+
+    //Executes the instruction at PC and returns the number of cycles it used
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> u8 {
+        self.instructions += 1;
+
+        //an halted cpu doesn't fetch anything until an interrupt wakes it up,
+        //but the time keeps passing
+        if self.stop {
+            self.last_cycles = 4;
+            self.total_cycles += 4;
+            return self.last_cycles;
+        }
+
+        //the trace line is formatted only if someone reads it
+        #[cfg(feature = "log")]
+        let log_trace = log_enabled!(Level::Trace);
+        #[cfg(not(feature = "log"))]
+        let log_trace = false;
+        if log_trace || self.ring_trace.is_some() {
+            let line = self.trace_line();
+            #[cfg(feature = "log")]
+            trace!("IN  {}", line);
+            if let Some(ring) = self.ring_trace.as_mut() {
+                ring.push_back(line);
+                if ring.len() > self.ring_trace_capacity {
+                    ring.pop_front();
+                }
+            }
+        }
+
+        let pc = self.register.pc;
+        self.last_cycles = 0;
+        self.ei_delay = false;
+        self.watch_hits.clear();
+        //the bytes supplied by interrupt_with_opcode are not fetched from memory
+        if let Some(stack_region) = &self.stack_region {
+            if self.injected.is_empty() && stack_region.contains(&pc) {
+                self.events.push(Event::ExecFromStack { addr: pc });
+            }
+        }
+
+        let mut opcode = self.get_next_byte();
+        if let Some(rewriter) = self.opcode_rewriter.as_mut() {
+            opcode = rewriter(pc, opcode);
+        }
+
+        if let Some(mut pre_exec) = self.pre_exec.take() {
+            let execute = pre_exec(self, opcode);
+            if self.pre_exec.is_none() {
+                self.pre_exec = Some(pre_exec);
+            }
+            if !execute {
+                self.injected.clear();
+                return 0;
+            }
+        }
+
+        if self.sandbox && opcode_is_restricted(opcode) {
+            self.events.push(Event::Restricted { opcode });
+            for _ in 1..get_length(opcode) {
+                self.get_next_byte();
+            }
+            return 0;
+        }
+
+        //the conditional CALL and RET replace it with the cycles they used
+        let mut cycles = get_cycles(opcode);
         match opcode {
             0x00 => { },                                                                //NOP
             0x01 => {                                                                   //LXI   B   SET REGISTER PAIR BC TO data
@@ -531,6 +926,7 @@ impl Cpu {
             0x05 => self.register.b = self.alu_dcr(self.register.b),                    //DCR   B   DECREMENT REGISTER B
             0x06 => self.register.b = self.get_next_byte(),                             //MVI   B,$ MOVE data INTO REGISTER B
             0x07 => self.alu_rlc(),                                                     //RLC       ROTATE ACCUMULATOR LEFT
+            0x08 => { },                                                                //NOP       UNDOCUMENTED
             0x09 => self.alu_dad(self.register.get_bc()),                               //DAD   B   ADD REGISTER PAIR BC TO HL
             0x0a => self.alu_ldax(self.register.get_bc()),                              //LDAX  B   LOAD ACCUMULATOR INDIRECT
             0x0b => self.register.set_bc(self.register.get_bc().wrapping_sub(1)),       //DCX   B   DECREMENT REGISTER PAIR BC
@@ -538,6 +934,7 @@ impl Cpu {
             0x0d => self.register.c = self.alu_dcr(self.register.c),                    //DCR   C   DECREMENT REGISTER C
             0x0e => self.register.c = self.get_next_byte(),                             //MVI   C,$ MOVE data INTO REGISTER C
             0x0f => self.alu_rrc(),                                                     //RRC       ROTATE ACCUMULATOR RIGHT 
+            0x10 => { },                                                                //NOP       UNDOCUMENTED
             0x11 => {                                                                   //LXI   D   SET REGISTER PAIR DE TO data
                 let value = self.get_next_word();
                 self.register.set_de(value);
@@ -548,6 +945,7 @@ impl Cpu {
             0x15 => self.register.d = self.alu_dcr(self.register.d),                    //DCR   D   DECREMENT REGISTER D
             0x16 => self.register.d = self.get_next_byte(),                             //MVI   D,$ MOVE data INTO REGISTER D
             0x17 => self.alu_ral(),                                                     //RAL       ROTATE ACCUMULATOR LEFT THROUGH CARRY
+            0x18 => { },                                                                //NOP       UNDOCUMENTED
             0x19 => self.alu_dad(self.register.get_de()),                               //DAD   D   ADD REGISTER PAIR DE TO HL
             0x1a => self.alu_ldax(self.register.get_de()),                              //LDAX  D   LOAD ACCUMULATOR INDIRECT
             0x1b => self.register.set_de(self.register.get_de().wrapping_sub(1)),       //DCX   D   DECREMENT REGISTER PAIR DE
@@ -555,6 +953,7 @@ impl Cpu {
             0x1d => self.register.e = self.alu_dcr(self.register.e),                    //DCR   E   DECREMENT REGISTER E
             0x1e => self.register.e = self.get_next_byte(),                             //MVI   E,$ MOVE data INTO REGISTER E
             0x1f => self.alu_rar(),                                                     //RAR       ROTATE ACCUMULATOR RIGHT THROUGH CARRY
+            0x20 => { },                                                                //NOP       UNDOCUMENTED
             0x21 => {                                                                   //LXI   H   SET REGISTER PAIR HL TO data
                 let value = self.get_next_word();
                 self.register.set_hl(value);
@@ -565,6 +964,7 @@ impl Cpu {
             0x25 => self.register.h = self.alu_dcr(self.register.h),                    //DCR   H   DECREMENT REGISTER H
             0x26 => self.register.h = self.get_next_byte(),                             //MVI   H,$ MOVE data INTO REGISTER H
             0x27 => self.alu_daa(),                                                     //DAA       DECIMAL ADJUST ACCUMULATION
+            0x28 => { },                                                                //NOP       UNDOCUMENTED
             0x29 => self.alu_dad(self.register.get_hl()),                               //DAD   H   ADD REGISTER PAIR HL TO HL
             0x2a => self.alu_lhld(),                                                    //LHLD  #   LOAD REGISTER PAIR HL DIRECT
             0x2b => self.register.set_hl(self.register.get_hl().wrapping_sub(1)),       //DCX   H   DECREMENT REGISTER PAIR HL
@@ -572,9 +972,13 @@ impl Cpu {
             0x2d => self.register.l = self.alu_dcr(self.register.l),                    //DCR   L   DECREMENT REGISTER L
             0x2e => self.register.l = self.get_next_byte(),                             //MVI   L,$ MOVE data INTO REGISTER L
             0x2f => self.alu_cma(),                                                     //CMA       COMPLEMENT ACCUMULATOR
-            0x31 => self.register.sp = self.get_next_word(),                            //LXI   SP  SET SP TO data
+            0x30 => { },                                                                //NOP       UNDOCUMENTED
+            0x31 => {                                                                   //LXI   SP  SET SP TO data
+                let value = self.get_next_word();
+                self.set_sp(value);
+            },
             0x32 => self.alu_sta(),                                                     //STA   #   STORE ACCUMULATOR DIRECT
-            0x33 => self.register.sp = self.register.sp.wrapping_add(1),                //INX   SP  INCREMENT REGISTER PAIR SP
+            0x33 => self.set_sp(self.register.sp.wrapping_add(1)),                      //INX   SP  INCREMENT REGISTER PAIR SP
             0x34 => {                                                                   //INR   M   INCREMENT memory 
                 let m = self.alu_inr(self.get_m());
                 self.set_m(m);
@@ -588,77 +992,20 @@ impl Cpu {
                 self.set_m(value);
             },
             0x37 => self.alu_stc(),                                                     //STC       SET CARRY 
+            0x38 => { },                                                                //NOP       UNDOCUMENTED
             0x39 => self.alu_dad(self.register.sp),                                     //DAD   SP  ADD STACK POINTER TO HL
             0x3a => self.alu_lda(),                                                     //LDA   #   LOAD ACCUMULATOR DIRECT 
-            0x3b => self.register.sp = self.register.sp.wrapping_sub(1),                //DCX   SP  DECREMENT REGISTER PAIR SP
+            0x3b => self.set_sp(self.register.sp.wrapping_sub(1)),                      //DCX   SP  DECREMENT REGISTER PAIR SP
             0x3c => self.register.a = self.alu_inr(self.register.a),                    //INR   A   INCREMENT REGISTER A
             0x3d => self.register.a = self.alu_dcr(self.register.a),                    //DCR   A   DECREMENT REGISTER A
             0x3e => self.register.a = self.get_next_byte(),                             //MVI   A,$ MOVE data INTO REGISTER A
             0x3f => self.alu_cmc(),                                                     //CMC       COMPLEMENT CARRY
-            0x40 => {},                                                                 //MOV   B,B MOVE REGISTER B INTO B
-            0x41 => self.register.b = self.register.c,                                  //MOV   B,C MOVE REGISTER C INTO B                  
-            0x42 => self.register.b = self.register.d,                                  //MOV   B,D MOVE REGISTER D INTO B
-            0x43 => self.register.b = self.register.e,                                  //MOV   B,E MOVE REGISTER E INTO B
-            0x44 => self.register.b = self.register.h,                                  //MOV   B,H MOVE REGISTER H INTO B
-            0x45 => self.register.b = self.register.l,                                  //MOV   B,L MOVE REGISTER L INTO B
-            0x46 => self.register.b = self.get_m(),                                     //MOV   B,M MOVE memory INTO B
-            0x47 => self.register.b = self.register.a,                                  //MOV   B,A MOVE REGISTER A INTO B
-            0x48 => self.register.c = self.register.b,                                  //MOV   C,B MOVE REGISTER B INTO C
-            0x49 => {},                                                                 //MOV   C,C MOVE REGISTER C INTO C
-            0x4a => self.register.c = self.register.d,                                  //MOV   C,D MOVE REGISTER D INTO C
-            0x4b => self.register.c = self.register.e,                                  //MOV   C,E MOVE REGISTER E INTO C
-            0x4c => self.register.c = self.register.h,                                  //MOV   C,H MOVE REGISTER H INTO C
-            0x4d => self.register.c = self.register.l,                                  //MOV   C,L MOVE REGISTER L INTO C
-            0x4e => self.register.c = self.get_m(),                                     //MOV   C,M MOVE memory INTO C
-            0x4f => self.register.c = self.register.a,                                  //MOV   C,A MOVE REGISTER A INTO C
-            0x50 => self.register.d = self.register.b,                                  //MOV   D,B MOVE REGISTER B INTO D
-            0x51 => self.register.d = self.register.c,                                  //MOV   D,C MOVE REGISTER C INTO D
-            0x52 => {},                                                                 //MOV   D,D MOVE REGISTER D INTO D
-            0x53 => self.register.d = self.register.e,                                  //MOV   D,E MOVE REGISTER E INTO D
-            0x54 => self.register.d = self.register.h,                                  //MOV   D,H MOVE REGISTER H INTO D
-            0x55 => self.register.d = self.register.l,                                  //MOV   D,L MOVE REGISTER L INTO D
-            0x56 => self.register.d = self.get_m(),                                     //MOV   D,M MOVE memory INTO B
-            0x57 => self.register.d = self.register.a,                                  //MOV   D,A MOVE REGISTER A INTO D
-            0x58 => self.register.e = self.register.b,                                  //MOV   E,B MOVE REGISTER B INTO E
-            0x59 => self.register.e = self.register.c,                                  //MOV   E,C MOVE REGISTER C INTO E
-            0x5a => self.register.e = self.register.d,                                  //MOV   E,D MOVE REGISTER D INTO E
-            0x5b => {},                                                                 //MOV   E,E MOVE REGISTER E INTO E
-            0x5c => self.register.e = self.register.h,                                  //MOV   E,H MOVE REGISTER H INTO E
-            0x5d => self.register.e = self.register.l,                                  //MOV   E,L MOVE REGISTER L INTO E
-            0x5e => self.register.e = self.get_m(),                                     //MOV   E,M MOVE memory INTO E
-            0x5f => self.register.e = self.register.a,                                  //MOV   E,A MOVE REGISTER A INTO E
-            0x60 => self.register.h = self.register.b,                                  //MOV   H,B MOVE REGISTER B INTO H
-            0x61 => self.register.h = self.register.c,                                  //MOV   H,C MOVE REGISTER C INTO H
-            0x62 => self.register.h = self.register.d,                                  //MOV   H,D MOVE REGISTER D INTO H
-            0x63 => self.register.h = self.register.e,                                  //MOV   H,E MOVE REGISTER E INTO H
-            0x64 => {},                                                                 //MOV   H,H MOVE REGISTER H INTO H
-            0x65 => self.register.h = self.register.l,                                  //MOV   H,L MOVE REGISTER L INTO H
-            0x66 => self.register.h = self.get_m(),                                     //MOV   H,M MOVE memory INTO H
-            0x67 => self.register.h = self.register.a,                                  //MOV   H,A MOVE REGISTER A INTO H
-            0x68 => self.register.l = self.register.b,                                  //MOV   L,B MOVE REGISTER B INTO L
-            0x69 => self.register.l = self.register.c,                                  //MOV   L,C MOVE REGISTER C INTO L
-            0x6a => self.register.l = self.register.d,                                  //MOV   L,D MOVE REGISTER D INTO L
-            0x6b => self.register.l = self.register.e,                                  //MOV   L,E MOVE REGISTER E INTO L
-            0x6c => self.register.l = self.register.h,                                  //MOV   L,H MOVE REGISTER J INTO L
-            0x6d => {},                                                                 //MOV   L,L MOVE REGISTER L INTO L
-            0x6e => self.register.l = self.get_m(),                                     //MOV   L,M MOVE memory INTO L
-            0x6f => self.register.l = self.register.a,                                  //MOV   L,A MOVE REGISTER A INTO L
-            0x70 => self.set_m(self.register.b),                                        //MOV   M,B MOVE REGISTER B INTO memory
-            0x71 => self.set_m(self.register.c),                                        //MOV   M,C MOVE REGISTER C INTO memory
-            0x72 => self.set_m(self.register.d),                                        //MOV   M,D MOVE REGISTER D INTO memory
-            0x73 => self.set_m(self.register.e),                                        //MOV   M,E MOVE REGISTER E INTO memory
-            0x74 => self.set_m(self.register.h),                                        //MOV   M,H MOVE REGISTER H INTO memory
-            0x75 => self.set_m(self.register.l),                                        //MOV   M,L MOVE REGISTER L INTO memory
-            0x76 => self.stop = true,                                                   //HLT   STOP THE CPU
-            0x77 => self.set_m(self.register.a),                                        //MOV   M,A MOVE REGISTER A INTO memory
-            0x78 => self.register.a = self.register.b,                                  //MOV   A,B MOVE REGISTER B INTO A
-            0x79 => self.register.a = self.register.c,                                  //MOV   A,C MOVE REGISTER C INTO A
-            0x7a => self.register.a = self.register.d,                                  //MOV   A,D MOVE REGISTER D INTO A
-            0x7b => self.register.a = self.register.e,                                  //MOV   A,E MOVE REGISTER E INTO A
-            0x7c => self.register.a = self.register.h,                                  //MOV   A,H MOVE REGISTER H INTO A
-            0x7d => self.register.a = self.register.l,                                  //MOV   A,L MOVE REGISTER L INTO A
-            0x7e => self.register.a = self.get_m(),                                     //MOV   A,M MOVE memory INTO A
-            0x7f => {},                                                                 //MOV   A,A MOVE REGISTER A INTO A
+            0x76 => {                                                                   //HLT   STOP THE CPU
+                #[cfg(feature = "log")]
+                debug!("Halted at {:04x}", pc);
+                self.stop = true;
+            },
+            0x40..=0x7f => self.alu_mov(Reg::from_code(opcode >> 3), Reg::from_code(opcode)), //MOV   D,S MOVE REGISTER S INTO D
             0x80 => self.alu_add(self.register.b),                                      //ADD   B   ADD B TO ACCUMULATOR
             0x81 => self.alu_add(self.register.c),                                      //ADD   C   ADD C TO ACCUMULATOR
             0x82 => self.alu_add(self.register.d),                                      //ADD   D   ADD D TO ACCUMULATOR
@@ -722,106 +1069,142 @@ impl Cpu {
             0xbc => self.alu_cmp(self.register.h),                                      //CMP   H   COMPARE H TO ACCUMULATOR
             0xbd => self.alu_cmp(self.register.l),                                      //CMP   L   COMPARE L TO ACCUMULATOR
             0xbe => self.alu_cmp(self.get_m()),                                         //CMP   M   COMPARE memory TO ACCUMULATOR
-            0xbf => self.alu_cmp(self.register.b),                                      //CMP   A   COMPARE A TO ACCUMULATOR
-            0xc0 => self.alu_ret(!self.register.get_flag(Flags::Zero)),                 //RNZ       RETURN IF NOT ZERO
+            0xbf => self.alu_cmp(self.register.a),                                      //CMP   A   COMPARE A TO ACCUMULATOR
+            0xc0 => cycles = self.alu_ret(!self.register.get_flag(Flags::Zero)),        //RNZ       RETURN IF NOT ZERO
             0xc1 => {                                                                   //POP   B   POP TOP OF THE STACK INTO REGISTER PAIR BC
                 let value = self.stack_pop();
                 self.register.set_bc(value);
             },                             
             0xc2 => self.alu_jmp(!self.register.get_flag(Flags::Zero)),                 //JNZ   #   JUMP TO ADDR IF NOT ZERO
             0xc3 => self.alu_jmp(true),                                                 //JUMP  #   JUMP TO ADDR UNCONDITIONALLY
-            0xc4 => self.alu_call(!self.register.get_flag(Flags::Zero)),                //CNZ   #   CALL ADDR IF NOT ZERO
+            0xc4 => cycles = self.alu_call(!self.register.get_flag(Flags::Zero)),       //CNZ   #   CALL ADDR IF NOT ZERO
             0xc5 => self.stack_push(self.register.get_bc()),                            //PUSH  B   PUSH REGISTER PAIR BC ON TOP OF THE STACK
             0xc6 => {                                                                   //ADI   #$  ADD data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_add(value);
             },
             0xc7 => self.alu_rst(0),                                                    //RST   0   RESET 0
-            0xc8 => self.alu_ret(self.register.get_flag(Flags::Zero)),                  //RN        RETURN IF ZERO
-            0xc9 => self.alu_ret(true),                                                 //RET       RETURN UNCONDITIONALLY
+            0xc8 => cycles = self.alu_ret(self.register.get_flag(Flags::Zero)),         //RN        RETURN IF ZERO
+            //RET keeps the 10 cycles of get_cycles, the 11 returned by alu_ret are for a taken Rcond
+            0xc9 => { self.alu_ret(true); },                                            //RET       RETURN UNCONDITIONALLY
             0xca => self.alu_jmp(self.register.get_flag(Flags::Zero)),                  //JZ    #   JUMP TO ADDR IF ZERO
-            0xcc => self.alu_call(self.register.get_flag(Flags::Zero)),                 //CZ    #   CALL ADDR IF ZERO
-            0xcd => self.alu_call(true),                                                //CALL  #   CALL addr UNCONDITIONALLY
+            0xcb => self.alu_jmp(true),                                                 //JMP   #   UNDOCUMENTED ALIAS OF 0xc3
+            0xcc => cycles = self.alu_call(self.register.get_flag(Flags::Zero)),        //CZ    #   CALL ADDR IF ZERO
+            0xcd => cycles = self.alu_call(true),                                       //CALL  #   CALL addr UNCONDITIONALLY
             0xce => {                                                                   //ACI   #$  ADD data TO ACCUMULATOR WITH CARRY
                 let value = self.get_next_byte();
                 self.alu_adc(value);
             },                                 
             0xcf => self.alu_rst(1),                                                    //RST   1   RESET 1
-            0xd0 => self.alu_ret(!self.register.get_flag(Flags::Carry)),                //RNC       RETURN IF NOT CARRY
+            0xd0 => cycles = self.alu_ret(!self.register.get_flag(Flags::Carry)),       //RNC       RETURN IF NOT CARRY
             0xd1 => {                                                                   //POP   D   POP TOP OF THE STACK INTO REGISTER PAIR DE
                 let value = self.stack_pop();
                 self.register.set_de(value);
             },                             
             0xd2 => self.alu_jmp(!self.register.get_flag(Flags::Carry)),                //JNC   #   JUMP TO ADDR IF NOT CARRY
-            0xd3 => { let _ = self.get_next_byte(); }                                   //OUT   port
-            0xd4 => self.alu_call(!self.register.get_flag(Flags::Carry)),               //CNC   #   CALL ADDR IF NOT CARRY
+            0xd3 => {                                                                   //OUT   port WRITE ACCUMULATOR TO port
+                let port = self.get_next_byte();
+                self.io.borrow_mut().output(port, self.register.a);
+            },
+            0xd4 => cycles = self.alu_call(!self.register.get_flag(Flags::Carry)),      //CNC   #   CALL ADDR IF NOT CARRY
             0xd5 => self.stack_push(self.register.get_de()),                            //PUSH  BD  PUSH REGISTER PAIR DE ON TOP OF THE STACK
             0xd6 => {                                                                   //SBB   #$  SUB data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_sub(value);  
             },                                 
             0xd7 => self.alu_rst(2),                                                    //RST   2   RESET 2
-            0xd8 => self.alu_ret(self.register.get_flag(Flags::Carry)),                 //RC        RETURN IF CARRY
+            0xd8 => cycles = self.alu_ret(self.register.get_flag(Flags::Carry)),        //RC        RETURN IF CARRY
+            //RET keeps the 10 cycles of get_cycles, the 11 returned by alu_ret are for a taken Rcond
+            0xd9 => { self.alu_ret(true); },                                            //RET       UNDOCUMENTED ALIAS OF 0xc9
             0xda => self.alu_jmp(self.register.get_flag(Flags::Carry)),                 //JC    #   JUMP NOT CARRY
-            0xdb => { let _ = self.get_next_byte(); }                                   //IN    port
-            0xdc => self.alu_call(self.register.get_flag(Flags::Carry)),                //CC    #    CALL ADDR CARRY
+            0xdb => {                                                                   //IN    port READ port INTO ACCUMULATOR
+                let port = self.get_next_byte();
+                self.register.a = self.io.borrow_mut().input(port);
+            },
+            0xdc => cycles = self.alu_call(self.register.get_flag(Flags::Carry)),       //CC    #    CALL ADDR CARRY
+            0xdd => cycles = self.alu_call(true),                                       //CALL  #   UNDOCUMENTED ALIAS OF 0xcd
             0xde => {                                                                   //SBI   #$  SUB data TO ACCUMULATOR WITH BORROW
                 let value = self.get_next_byte();
                 self.alu_sbb(value);
             },                                 
             0xdf => self.alu_rst(3),                                                    //RST   3   RESET 3
-            0xe0 => self.alu_ret(!self.register.get_flag(Flags::Parity)),               //RPO       RETURN IF PARITY ODD
+            0xe0 => cycles = self.alu_ret(!self.register.get_flag(Flags::Parity)),      //RPO       RETURN IF PARITY ODD
             0xe1 => {                                                                   //POP   H   POP TOP OF THE STACK INTO REGISTER PAIR HL
                 let value = self.stack_pop();
                 self.register.set_hl(value);
             },                             
             0xe2 => self.alu_jmp(!self.register.get_flag(Flags::Parity)),               //JPO   #   JUMP TO ADDR IF PARITY ODD
             0xe3 => self.alu_xthl(),                                                    //XTHL      EXCHANGE REGISTER PARI HL WITH STACK TOP
-            0xe4 => self.alu_call(!self.register.get_flag(Flags::Parity)),              //CPO   #   CALL ADDR IF PARITY ODD
+            0xe4 => cycles = self.alu_call(!self.register.get_flag(Flags::Parity)),     //CPO   #   CALL ADDR IF PARITY ODD
             0xe5 => self.stack_push(self.register.get_hl()),                            //PUSH  H   PUSH REGISTER PAIR HL ON TOP OF THE STACK
             0xe6 => {                                                                   //ANI   #$  AND data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_ana(value);
             },                                 
             0xe7 => self.alu_rst(4),                                                    //RST   4   RESET 4
-            0xe8 => self.alu_ret(self.register.get_flag(Flags::Parity)),                //RPE       RETURN IF PARITY EVEN
+            0xe8 => cycles = self.alu_ret(self.register.get_flag(Flags::Parity)),       //RPE       RETURN IF PARITY EVEN
             0xe9 => self.register.pc = self.register.get_hl(),                          //PCHL      SET PC TO REGISTER PAIR HL
             0xea => self.alu_jmp(self.register.get_flag(Flags::Parity)),                //JPE   #   JUMP TO ADDR IF PARITY EVEN
             0xeb => self.alu_xchg(),                                                    //XCHG      EXCHANGE H WITH D AND L WITH E
-            0xec => self.alu_call(self.register.get_flag(Flags::Parity)),               //CPE   #   CALL ADDR IF PARITY EVEN
+            0xec => cycles = self.alu_call(self.register.get_flag(Flags::Parity)),      //CPE   #   CALL ADDR IF PARITY EVEN
+            0xed => cycles = self.alu_call(true),                                       //CALL  #   UNDOCUMENTED ALIAS OF 0xcd
             0xee => {                                                                   //XRI   #$  XOR data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_xra(value);
             },                                 
             0xef => self.alu_rst(5),                                                    //RST   5   RESET 5
-            0xf0 => self.alu_ret(!self.register.get_flag(Flags::Sign)),                 //RP        RETURN IF POSITIVE
+            0xf0 => cycles = self.alu_ret(!self.register.get_flag(Flags::Sign)),        //RP        RETURN IF POSITIVE
             0xf1 => {                                                                   //POP   PSW POP TOP OF THE STACK INTO AF
                 let value = self.stack_pop();
                 self.register.set_af(value);
             },                             
             0xf2 => self.alu_jmp(!self.register.get_flag(Flags::Sign)),                 //JP    #   JUMP TO ADDR IF POSITIVE
             0xf3 => self.interrupt = false,                                             //DI        DISABLE INTERRUPTS
-            0xf4 => self.alu_call(!self.register.get_flag(Flags::Sign)),                //CP    #   CALL ADDR IF POSITIVE
+            0xf4 => cycles = self.alu_call(!self.register.get_flag(Flags::Sign)),       //CP    #   CALL ADDR IF POSITIVE
             0xf5 => self.stack_push(self.register.get_af()),                            //PUSH  PSW PUSH AF ON TOP OF THE STACK
             0xf6 => {                                                                   //ORI   #$  OR data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_ora(value);
             },                                
             0xf7 => self.alu_rst(6),                                                    //RST   6   RESET 6
-            0xf8 => self.alu_ret(self.register.get_flag(Flags::Sign)),                  //RM        RETURN IF NEGATIVE
-            0xf9 => self.register.sp = self.register.get_hl(),                          //SPHL      SET STACK TOP TO REGISTER PAIR HL
+            0xf8 => cycles = self.alu_ret(self.register.get_flag(Flags::Sign)),         //RM        RETURN IF NEGATIVE
+            0xf9 => self.set_sp(self.register.get_hl()),                                //SPHL      SET STACK TOP TO REGISTER PAIR HL
             0xfa => self.alu_jmp(self.register.get_flag(Flags::Sign)),                  //JM    #   JUMP TO ADDR IF NEGATIVE
-            0xfb => self.interrupt = true,                                              //EI        ENABLE INTERRUPTS
-            0xfc => self.alu_call(self.register.get_flag(Flags::Sign)),                 //CN    #   CALL ADDR IF NEGATIVE
+            0xfb => {                                                                   //EI        ENABLE INTERRUPTS AFTER THE NEXT INSTRUCTION
+                self.interrupt = true;
+                self.ei_delay = true;
+            },
+            0xfc => cycles = self.alu_call(self.register.get_flag(Flags::Sign)),        //CN    #   CALL ADDR IF NEGATIVE
+            0xfd => cycles = self.alu_call(true),                                       //CALL  #   UNDOCUMENTED ALIAS OF 0xcd
             0xfe => {                                                                   //CPI   #$  COMPARE data TO ACCUMULATOR
                 let value = self.get_next_byte();
                 self.alu_cmp(value);
             },                                 
             0xff => self.alu_rst(7),                                                    //RST   7   RESET 7
-            _ => unimplemented!(),
         };
 
-        debug!(
+        self.self_jump = (opcode == 0xc3 || opcode == 0xcb) && self.register.pc == pc;
+
+        self.last_cycles = cycles;
+
+        if let Some(attribution) = self.cycle_attribution.as_mut() {
+            *attribution.entry(pc).or_insert(0) += u64::from(self.last_cycles);
+        }
+
+        if let Some(profile) = self.branch_profile.as_mut() {
+            if is_conditional_branch(opcode) {
+                let counts = profile.entry(pc).or_insert((0, 0));
+                if self.register.pc == pc.wrapping_add(get_length(opcode) as u16) {
+                    counts.1 += 1;
+                }
+                else {
+                    counts.0 += 1;
+                }
+            }
+        }
+
+        #[cfg(feature = "log")]
+        trace!(
             "OUT {:04x} {} PC={:04x} SP={:04x} A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x}",
             opcode,
             get_mnemonic(opcode),
@@ -836,15 +1219,2351 @@ impl Cpu {
             self.register.h,
             self.register.l
         );
+
+        self.total_cycles += u64::from(self.last_cycles);
+        self.last_cycles
+    }
+
+    //Requests the interrupt RST rst (0-7): if the interrupts are enabled the PC is pushed,
+    //the cpu jumps to rst * 8 and the interrupts are disabled
+    pub fn interrupt(&mut self, rst: u8) {
+        self.interrupt_handler(u16::from(rst & 0x07) * 8);
+    }
+
+    //Requests an interrupt acknowledged with an instruction supplied by an external device,
+    //usually a RST but it can be a CALL: opcode and operands are executed by the next call to
+    //next() instead of the instruction at PC, which is not moved while they are fetched.
+    //Like interrupt(), nothing happens if the interrupts are disabled
+    pub fn interrupt_with_opcode(&mut self, opcode: u8, operands: &[u8]) {
+        assert_eq!(
+            operands.len(),
+            get_length(opcode) - 1,
+            "wrong number of operands for {}",
+            get_mnemonic(opcode).trim_end()
+        );
+        if !self.interrupt || self.ei_delay {
+            return;
+        }
+
+        #[cfg(feature = "log")]
+        debug!("Interrupt at {:04x}, executing {:02x}", self.register.pc, opcode);
+        self.interrupt = false;
+        self.stop = false;
+        self.self_jump = false;
+        self.injected.clear();
+        self.injected.push_back(opcode);
+        self.injected.extend(operands);
     }
 
     pub fn interrupt_handler(&mut self, addr: u16) {
-        if !self.interrupt{
+        if !self.interrupt || self.ei_delay {
             return;
         }
 
+        #[cfg(feature = "log")]
+        debug!("Interrupt at {:04x}, jumping to {:04x}", self.register.pc, addr);
         self.interrupt = false;
+        self.stop = false;
+        self.self_jump = false;
         self.stack_push(self.register.pc);
         self.register.pc = addr;
     }
-}
\ No newline at end of file
+}
+
+// This impl block implements the tools used to trace the execution
+impl Cpu {
+    //Returns a line describing the instruction at PC and the state of the registers
+    //before it is executed
+    pub fn trace_line(&self) -> String {
+        let opcode = self.pending_opcode();
+        format!(
+            "{:04x} {} PC={:04x} SP={:04x} A={:02x} F={:02x} B={:02x} C={:02x} D={:02x} E={:02x} H={:02x} L={:02x}",
+            opcode,
+            get_mnemonic(opcode),
+            self.register.pc,
+            self.register.sp,
+            self.register.a,
+            self.register.f,
+            self.register.b,
+            self.register.c,
+            self.register.d,
+            self.register.e,
+            self.register.h,
+            self.register.l
+        )
+    }
+
+    //Keeps the trace_line of the last `capacity` executed instructions,
+    //calling it again clears the ones already recorded
+    pub fn enable_ring_trace(&mut self, capacity: usize) {
+        self.ring_trace = Some(VecDeque::with_capacity(capacity));
+        self.ring_trace_capacity = capacity;
+    }
+
+    //Returns the recorded trace lines, from the oldest to the most recent
+    pub fn ring_trace(&self) -> Vec<String> {
+        match &self.ring_trace {
+            Some(ring) => ring.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    //Returns a table with the value of every register and flag, e.g.
+    //A  F  B  C  D  E  H  L  SP   PC   S Z A P C
+    //00 02 00 00 00 00 00 00 0000 0000 0 0 0 0 0
+    pub fn state_table(&self) -> String {
+        format!(
+            "A  F  B  C  D  E  H  L  SP   PC   S Z A P C\n\
+             {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:02x} {:04x} {:04x} {} {} {} {} {}",
+            self.register.a,
+            self.register.f,
+            self.register.b,
+            self.register.c,
+            self.register.d,
+            self.register.e,
+            self.register.h,
+            self.register.l,
+            self.register.sp,
+            self.register.pc,
+            self.register.get_flag(Flags::Sign) as u8,
+            self.register.get_flag(Flags::Zero) as u8,
+            self.register.get_flag(Flags::AC) as u8,
+            self.register.get_flag(Flags::Parity) as u8,
+            self.register.get_flag(Flags::Carry) as u8
+        )
+    }
+}
+
+// This impl block implements the tools used by debuggers to inspect the program
+impl Cpu {
+    pub fn register(&self) -> &Register {
+        &self.register
+    }
+
+    pub fn register_mut(&mut self) -> &mut Register {
+        &mut self.register
+    }
+
+    pub fn flag(&self, flag: Flags) -> bool {
+        self.register.get_flag(flag)
+    }
+
+    //Forces a flag, e.g. to prepare the state before executing a single opcode
+    pub fn set_flag(&mut self, flag: Flags, value: bool) {
+        self.register.set_flag(flag, value);
+    }
+
+    //Returns the address the instruction at PC will jump to if the branch is taken:
+    //PCHL uses HL, RET uses the top of the stack, RST its vector and
+    //JMP/CALL their immediate address. None if the instruction is not a branch
+    pub fn pending_branch_target(&self) -> Option<u16> {
+        let bytes = self.pending_bytes();
+        match bytes[0] {
+            0xe9 => Some(self.register.get_hl()),
+            0xc0 | 0xc8 | 0xc9 | 0xd0 | 0xd8 | 0xd9 | 0xe0 | 0xe8 | 0xf0 | 0xf8 => {
+                Some(self.memory.borrow().get_word(usize::from(self.register.sp)))
+            },
+            0xc2 | 0xc3 | 0xca | 0xcb | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa |
+            0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xdd | 0xe4 | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => {
+                Some(u16::from(bytes[1]) | (u16::from(bytes[2]) << 8))
+            },
+            opcode if opcode & 0xc7 == 0xc7 => Some(u16::from(opcode & 0x38)),
+            _ => None,
+        }
+    }
+
+    //Executes the instruction at PC and returns what it did
+    pub fn step_verbose(&mut self) -> VerboseStep {
+        let before = self.register.clone();
+        let opcode = self.pending_opcode();
+        let cycles = self.next();
+
+        let changes = before
+            .values()
+            .iter()
+            .zip(self.register.values().iter())
+            .filter(|(old, new)| old.1 != new.1)
+            .map(|(old, new)| (old.0, old.1, new.1))
+            .collect();
+
+        VerboseStep {
+            addr: before.pc,
+            mnemonic: get_mnemonic(opcode).trim_end(),
+            cycles,
+            changes,
+        }
+    }
+
+    //Executes the instruction at PC and returns it disassembled with the registers it left
+    pub fn step_traced(&mut self) -> StepTrace {
+        let pc = self.register.pc;
+        let bytes = self.pending_bytes();
+        let (mnemonic, _) = decode(&bytes, 0);
+        self.next();
+
+        StepTrace {
+            pc,
+            mnemonic,
+            bytes,
+            register: self.register.clone(),
+        }
+    }
+
+    //Executes opcode with its immediate operands instead of the instruction at PC and returns
+    //the cycles used. PC is not moved while they are fetched: a JMP sets it, an ADD leaves it
+    //untouched. Like next(), a halted cpu doesn't execute anything
+    pub fn exec_opcode(&mut self, opcode: u8, operands: &[u8]) -> u8 {
+        assert_eq!(
+            operands.len(),
+            get_length(opcode) - 1,
+            "wrong number of operands for {}",
+            get_mnemonic(opcode).trim_end()
+        );
+        self.injected.clear();
+        self.injected.push_back(opcode);
+        self.injected.extend(operands);
+        let cycles = self.next();
+        self.injected.clear();
+        cycles
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) {
+        self.breakpoints.remove(&addr);
+    }
+
+    pub fn add_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.insert(addr);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        self.watchpoints.remove(&addr);
+    }
+
+    //Executes the instruction at PC even if the cpu is halted and returns the cycles used,
+    //the HLT is left behind without waiting for an interrupt
+    pub fn step_force(&mut self) -> u8 {
+        self.stop = false;
+        self.next()
+    }
+
+    //Executes the instruction at PC and returns Write(addr) if it wrote to a watchpoint,
+    //otherwise Pc(addr) if the PC reached a breakpoint, so it's stopped before running it
+    pub fn step_debug(&mut self) -> Option<DebugHit> {
+        self.next();
+        if let Some(&addr) = self.watch_hits.first() {
+            Some(DebugHit::Write(addr))
+        }
+        else if self.breakpoints.contains(&self.register.pc) {
+            Some(DebugHit::Pc(self.register.pc))
+        }
+        else {
+            None
+        }
+    }
+
+    //Returns the number of cycles used by the last instruction executed,
+    //0 if it was skipped by the pre-execution function or by the sandbox
+    pub fn last_cycles(&self) -> u8 {
+        self.last_cycles
+    }
+
+    //Returns the cycles used since the cpu was created or reset, halted cycles included
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    //Returns the address of the instruction that follows the one at PC in memory,
+    //whether or not it's a branch. An instruction supplied by interrupt_with_opcode
+    //doesn't move the PC, so it's followed by the one at PC
+    pub fn fallthrough_pc(&self) -> u16 {
+        if !self.injected.is_empty() {
+            return self.register.pc;
+        }
+        let opcode = self.memory.borrow().get(usize::from(self.register.pc));
+        self.register.pc.wrapping_add(get_length(opcode) as u16)
+    }
+
+    //Executes the instruction at PC, if it's a CALL or a RST the subroutine is run until it
+    //returns to the instruction after the call. Recursive calls of the same subroutine are
+    //handled comparing the SP with the one before the call.
+    //At most max_steps instructions are executed
+    pub fn step_over(&mut self, max_steps: u64) -> RunOutcome {
+        if max_steps == 0 {
+            return RunOutcome::StepLimit;
+        }
+
+        let opcode = self.pending_opcode();
+        let return_addr = self.fallthrough_pc();
+        let sp = self.register.sp;
+        self.next();
+
+        if is_call(opcode) {
+            let mut steps = 1;
+            while self.register.pc != return_addr || self.register.sp < sp {
+                if self.stop {
+                    return RunOutcome::Halted;
+                }
+                if steps == max_steps {
+                    return RunOutcome::StepLimit;
+                }
+                self.next();
+                steps += 1;
+            }
+        }
+
+        if self.stop {
+            RunOutcome::Halted
+        }
+        else {
+            RunOutcome::Completed
+        }
+    }
+
+    //Returns the INTE flip-flop, the only interrupt state of the i8080: EI sets it and
+    //DI or accepting an interrupt clear it. It's not part of the PSW.
+    //EI sets it immediately but the interrupts are accepted only after the instruction
+    //that follows EI, so that EI; RET can return before the next interrupt
+    pub fn interrupt_flipflop(&self) -> bool {
+        self.interrupt
+    }
+
+    //Returns the value the Parity flag takes when an instruction produces value:
+    //the i8080 uses even parity over all the 8 bits, e.g. 0x03 is even and 0x07 is odd
+    pub fn would_parity_be_even(value: u8) -> bool {
+        PARITY[usize::from(value)]
+    }
+
+    //Returns true if an interrupt requested now would be accepted: unlike interrupt_flipflop
+    //it is false for the instruction that follows EI
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupt && !self.ei_delay
+    }
+
+    //Returns true if the cpu executed a HLT and is waiting for an interrupt
+    pub fn is_halted(&self) -> bool {
+        self.stop
+    }
+
+    //Returns true if the cpu can't do anything else: it is halted with the interrupts
+    //disabled or the last instruction was a JMP to itself
+    pub fn is_idle(&self) -> bool {
+        (self.stop && !self.interrupt) || self.self_jump
+    }
+
+    //Sets a function called with the opcode after it is fetched and before it is executed,
+    //when it returns false the instruction is skipped.
+    //When the function is called PC already points to the byte after the opcode, while
+    //the operands are not fetched yet: skipping an instruction with operands leaves
+    //PC on them unless the function moves it
+    pub fn set_pre_exec<F: FnMut(&mut Cpu, u8) -> bool + 'static>(&mut self, pre_exec: F) {
+        self.pre_exec = Some(Box::new(pre_exec));
+    }
+
+    //Returns the disassembly of the before instructions that precede PC, the instruction
+    //at PC, marked with a '>', and the after instructions that follow it, one per line
+    //with the operands (e.g. "> 0002 MVI A,$05").
+    //The instructions before PC are found with disassemble_backward, there are fewer lines
+    //when PC is near the start or the end of memory
+    pub fn disassemble_around(&self, before: usize, after: usize) -> String {
+        let pc = usize::from(self.register.pc);
+        let start = pc.saturating_sub(before * 3);
+        let bytes: Vec<u8> = {
+            let memory = self.memory.borrow();
+            let end = (pc + (after + 1) * 3).min(memory.len());
+            (start..end).map(|idx| memory.get(idx)).collect()
+        };
+
+        let mut offsets: Vec<usize> = disassemble_backward(&bytes, pc - start, before)
+            .iter()
+            .map(|instruction| instruction.addr)
+            .collect();
+        let mut pos = pc - start;
+        for _ in 0..=after {
+            if pos >= bytes.len() {
+                break;
+            }
+            offsets.push(pos);
+            pos += get_length(bytes[pos]);
+        }
+
+        offsets
+            .into_iter()
+            .map(|offset| {
+                let addr = start + offset;
+                let marker = if addr == pc { '>' } else { ' ' };
+                format!("{} {:04x} {}", marker, addr, decode(&bytes, offset).0)
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    //Sets a function called with the address and the value of every fetched opcode,
+    //the cpu executes the opcode it returns. The memory is not modified
+    pub fn set_opcode_rewriter<F: FnMut(u16, u8) -> u8 + 'static>(&mut self, opcode_rewriter: F) {
+        self.opcode_rewriter = Some(Box::new(opcode_rewriter));
+    }
+
+    //Sets a function called with the old and the new value of the SP every time an instruction
+    //or an interrupt changes it, e.g. to graph the depth of the stack
+    pub fn set_sp_hook<F: FnMut(u16, u16) + 'static>(&mut self, sp_hook: F) {
+        self.sp_hook = Some(Box::new(sp_hook));
+    }
+
+    //Returns an hash of the registers, the control flags and the memory,
+    //useful to find out when two runs diverge or when a program loops.
+    //The hash is the 64 bit FNV-1a of A, F, B, C, D, E, H, L, SP and PC (low byte first),
+    //HLT, INTE and the EI delay (1 when set) and every byte of the memory, so it's the same
+    //across builds and platforms. See: http://www.isthe.com/chongo/tech/comp/fnv/
+    pub fn state_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        let fnv = |hash: u64, byte: u8| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+
+        let register = &self.register;
+        let [sp_low, sp_high] = register.sp.to_le_bytes();
+        let [pc_low, pc_high] = register.pc.to_le_bytes();
+        let state = [
+            register.a, register.f, register.b, register.c, register.d, register.e, register.h, register.l,
+            sp_low, sp_high, pc_low, pc_high,
+            u8::from(self.stop), u8::from(self.interrupt), u8::from(self.ei_delay),
+        ];
+        let hash = state.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| fnv(hash, byte));
+
+        let memory = self.memory.borrow();
+        (0..memory.len()).fold(hash, |hash, idx| fnv(hash, memory.get(idx)))
+    }
+
+    //Returns the word on top of the stack, the address a RET would jump to, without popping it
+    pub fn peek_return_address(&self) -> u16 {
+        self.memory.borrow().get_word(usize::from(self.register.sp))
+    }
+}
+
+// This impl block implements the tools used to profile the program
+impl Cpu {
+    //Starts counting how many times each conditional branch is taken or not taken,
+    //calling it again clears the counts already recorded
+    pub fn enable_branch_profile(&mut self) {
+        self.branch_profile = Some(BTreeMap::new());
+    }
+
+    //Returns, for the address of every conditional branch executed, how many times
+    //it was (taken, not taken)
+    pub fn branch_profile(&self) -> BTreeMap<u16, (u64, u64)> {
+        self.branch_profile.clone().unwrap_or_default()
+    }
+
+    //Starts adding up the cycles spent executing the instruction at each address,
+    //calling it again clears the totals already recorded
+    pub fn enable_cycle_attribution(&mut self) {
+        self.cycle_attribution = Some(BTreeMap::new());
+    }
+
+    //Returns, for the address of every instruction executed, the total cycles spent on it
+    pub fn cycle_attribution(&self) -> BTreeMap<u16, u64> {
+        self.cycle_attribution.clone().unwrap_or_default()
+    }
+}
+
+// This impl block implements the tools used to run untrusted programs
+impl Cpu {
+    //In sandbox mode the restricted opcodes (see opcode_is_restricted) are skipped,
+    //operands included, and an Event::Restricted is recorded instead
+    pub fn set_sandbox(&mut self, sandbox: bool) {
+        self.sandbox = sandbox;
+    }
+
+    //Records an Event::ExecFromStack every time an instruction is fetched between low and high
+    //(included), executing the stack is usually a sign that it has been corrupted
+    pub fn set_stack_region(&mut self, low: u16, high: u16) {
+        self.stack_region = Some(low..=high);
+    }
+
+    //Returns the events recorded since the last clear_events
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn clear_events(&mut self) {
+        self.events.clear();
+    }
+}
+
+// This impl block implements the loops used to run the cpu
+impl Cpu {
+    //Stops the run_* loops after limit calls to next() counted from now, halted ones included,
+    //so that a program stuck in a loop can't hang the host. None, the default, removes the limit
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+        self.instructions = 0;
+    }
+
+    //Returns true if the run_* loops can't run anything because of set_instruction_limit
+    pub fn instruction_limit_reached(&self) -> bool {
+        matches!(self.instruction_limit, Some(limit) if self.instructions >= limit)
+    }
+
+    //Runs the cpu until it halts or until the instruction limit is reached
+    pub fn run(&mut self) -> RunOutcome {
+        while !self.stop {
+            if self.instruction_limit_reached() {
+                return RunOutcome::InstructionLimit;
+            }
+            self.next();
+        }
+        RunOutcome::Halted
+    }
+
+    //Runs a CP/M test program (e.g. CPUDIAG, 8080PRE, TST8080) and returns what it printed.
+    //The program has to be loaded at 0x0100 with load_program.
+    //The BDOS calls (CALL 0x0005) are replaced by a stub that supports the function in C:
+    //  2: prints the character in E
+    //  9: prints the string at DE terminated by '$'
+    //the program ends when it jumps to 0x0000 (warm boot) or halts.
+    //A string without '$' in the whole memory and the instruction limit end it too,
+    //with a line saying so in the output
+    pub fn run_cpm_test(&mut self) -> String {
+        self.run_cpm_test_with(CpmConfig::default())
+    }
+
+    //Same as run_cpm_test with the exit and BDOS addresses of config
+    pub fn run_cpm_test_with(&mut self, config: CpmConfig) -> String {
+        let mut output = String::new();
+        while !self.stop && self.register.pc != config.exit {
+            if self.instruction_limit_reached() {
+                output.push_str("\ninstruction limit reached\n");
+                break;
+            }
+            if self.register.pc != config.bdos {
+                self.next();
+                continue;
+            }
+
+            match self.register.c {
+                2 => output.push(char::from(self.register.e)),
+                9 => {
+                    let memory = self.memory.borrow();
+                    let start = self.register.get_de();
+                    let mut addr = start;
+                    let mut length = 0;
+                    while memory.get(usize::from(addr)) != b'$' {
+                        if length == 0x10000 {
+                            output.push_str(&format!("\nBDOS 9: the string at {:04x} has no '$'\n", start));
+                            return output;
+                        }
+                        output.push(char::from(memory.get(usize::from(addr))));
+                        addr = addr.wrapping_add(1);
+                        length += 1;
+                    }
+                },
+                _ => {},
+            }
+            self.register.pc = self.stack_pop();
+        }
+        output
+    }
+
+    //Runs at most max instructions stopping earlier if the cpu halts or the instruction limit
+    //is reached, returns the number of instructions executed
+    pub fn run_steps(&mut self, max: usize) -> usize {
+        let mut steps = 0;
+        while steps < max && !self.stop && !self.instruction_limit_reached() {
+            self.next();
+            steps += 1;
+        }
+        steps
+    }
+
+    //Runs the cpu until deadline, until it halts or until the instruction limit is reached
+    //and returns the cycles used. The clock is read every 1000 instructions, so the deadline
+    //can be passed by the time needed to run them
+    pub fn run_until_instant(&mut self, deadline: Instant) -> u32 {
+        let mut cycles = 0;
+        while !self.stop && !self.instruction_limit_reached() && Instant::now() < deadline {
+            for _ in 0..1000 {
+                if self.stop || self.instruction_limit_reached() {
+                    break;
+                }
+                cycles += u32::from(self.next());
+            }
+        }
+        cycles
+    }
+
+    //Runs a Space Invaders frame: cycles_per_frame cycles with RST 1 requested in the middle
+    //of the frame and RST 2 at the end (see SpaceInvadersTiming), then returns a copy of the video RAM.
+    //The interrupts requested while they are disabled are deferred, not dropped: like a device
+    //that holds its request until it's acknowledged, each RST is delivered once, through
+    //interrupt_with_opcode, as soon as the program enables the interrupts again (after the
+    //instruction that follows EI), also in a later frame.
+    //The frame is cut short when the instruction limit is reached
+    pub fn run_frame(&mut self, cycles_per_frame: u32) -> Vec<u8> {
+        let timing = SpaceInvadersTiming::new(cycles_per_frame);
+        let mut cycles = 0;
+        while cycles < timing.end_frame() && !self.instruction_limit_reached() {
+            if self.interrupts_enabled() {
+                if let Some(rst) = self.deferred_interrupts.pop_front() {
+                    self.interrupt_with_opcode(0xc7 | (rst << 3), &[]);
+                }
+            }
+
+            let next = cycles + u32::from(self.next());
+            for rst in timing.interrupts(cycles, next) {
+                if self.interrupts_enabled() {
+                    self.interrupt(rst);
+                }
+                else if !self.deferred_interrupts.contains(&rst) {
+                    self.deferred_interrupts.push_back(rst);
+                }
+            }
+            cycles = next;
+        }
+
+        let memory = self.memory.borrow();
+        VIDEO_RAM.map(|idx| memory.get(idx)).collect()
+    }
+}
+
+// This impl block implements the save states
+impl Cpu {
+    //Returns a copy of the registers, of the interrupt state, of the whole memory and of the devices
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {
+            register: self.register.clone(),
+            stop: self.stop,
+            interrupt: self.interrupt,
+            ei_delay: self.ei_delay,
+            self_jump: self.self_jump,
+            injected: self.injected.iter().copied().collect(),
+            deferred_interrupts: self.deferred_interrupts.iter().copied().collect(),
+            total_cycles: self.total_cycles,
+            memory: self.memory.borrow().save_state(),
+            io: self.io.borrow().save_state(),
+        }
+    }
+
+    //Brings the cpu, its memory and its devices back to state, with the default Memory::restore_state
+    //the writes go through Memory::set so a memory that ignores some writes (e.g. RomRam)
+    //keeps ignoring them
+    pub fn restore(&mut self, state: &CpuState) {
+        self.register = state.register.clone();
+        self.stop = state.stop;
+        self.interrupt = state.interrupt;
+        self.ei_delay = state.ei_delay;
+        self.self_jump = state.self_jump;
+        self.injected = state.injected.iter().copied().collect();
+        self.deferred_interrupts = state.deferred_interrupts.iter().copied().collect();
+        self.total_cycles = state.total_cycles;
+        self.memory.borrow_mut().restore_state(&state.memory);
+        self.io.borrow_mut().restore_state(&state.io);
+    }
+
+    //Fixes the reserved bits of F, useful after loading a state from an external source
+    pub fn normalize_flags(&mut self) {
+        self.register.normalize();
+    }
+}
+
+// This impl block implements the self test of the ALU
+impl Cpu {
+    //Runs every operation of SELF_TEST on a new cpu and compares A and F with the known results,
+    //returns the first operation that differs
+    pub fn self_test() -> Result<(), SelfTestError> {
+        Cpu::self_test_with(&SELF_TEST)
+    }
+
+    //Runs the operations of cases, written like the rows of SELF_TEST
+    fn self_test_with(cases: &[SelfTestCase]) -> Result<(), SelfTestError> {
+        for &(operation, a, b, carry, opcode, expected_a, expected_f) in cases.iter() {
+            let mut program = vec![0x3e, a, 0x06, b];     //MVI A, a; MVI B, b
+            if carry {
+                program.push(0x37);                         //STC
+            }
+            program.push(opcode);
+            program.push(0x76);                             //HLT
+
+            let mut cpu = Cpu::new(Rc::new(RefCell::new(Linear::new())));
+            cpu.load_program(0x0000, &program);
+            cpu.run();
+
+            if (cpu.register.a, cpu.register.f) != (expected_a, expected_f) {
+                return Err(SelfTestError {
+                    operation,
+                    expected: (expected_a, expected_f),
+                    found: (cpu.register.a, cpu.register.f),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// This impl prints the registers and the control flags,
+// the memory is printed as its length to keep the output short
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cpu")
+            .field("register", &self.register)
+            .field("stop", &self.stop)
+            .field("interrupt", &self.interrupt)
+            .field("memory_len", &self.memory.borrow().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::memory::BankedMemory;
+
+    //Returns a cpu with `program` loaded at 0x0000 of a 64KB memory
+    fn cpu_with(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(Rc::new(RefCell::new(Linear::new())));
+        cpu.load_program(0, program);
+        cpu
+    }
+
+    #[test]
+    fn ring_trace_keeps_the_most_recent_lines() {
+        let mut cpu = cpu_with(&[0x00; 8]);
+        cpu.enable_ring_trace(3);
+        for _ in 0..5 {
+            cpu.next();
+        }
+
+        let ring = cpu.ring_trace();
+        assert_eq!(ring.len(), 3);
+        assert!(ring[0].contains("PC=0002"));
+        assert!(ring[2].contains("PC=0004"));
+    }
+
+    #[test]
+    fn machine_control_instructions() {
+        //NOP; EI; NOP; DI; HLT
+        let mut cpu = cpu_with(&[0x00, 0xfb, 0x00, 0xf3, 0x76]);
+        let before = cpu.register.clone();
+
+        assert_eq!(cpu.next(), 4);
+        assert_eq!(cpu.register.pc, 1);
+        let mut expected = before;
+        expected.pc = 1;
+        assert_eq!(cpu.register.values(), expected.values());
+
+        assert_eq!(cpu.next(), 4);
+        assert!(cpu.interrupt_flipflop());
+        assert!(!cpu.interrupts_enabled());
+        assert_eq!(cpu.next(), 4);
+        assert!(cpu.interrupts_enabled());
+
+        assert_eq!(cpu.next(), 4);
+        assert!(!cpu.interrupts_enabled());
+
+        assert_eq!(cpu.next(), 7);
+        assert!(cpu.is_halted());
+    }
+
+
+    //Prints HL in decimal with five digits through BDOS 9, the digits are
+    //found doubling a BCD number in E:B:C for every bit of HL (double dabble)
+    const PRINT_HL: [u8; 70] = [
+        0x31, 0x00, 0x02,       //0100 LXI SP,$0200
+        0x01, 0x00, 0x00,       //0103 LXI B,$0000      BCD low digits
+        0x11, 0x00, 0x10,       //0106 LXI D,$1000      D: 16 bits to shift, E: BCD high digit
+        0x29,                   //0109 DAD H            next bit of HL in carry
+        0x79, 0x8f, 0x27, 0x4f, //010A MOV A,C; ADC A; DAA; MOV C,A
+        0x78, 0x8f, 0x27, 0x47, //010E MOV A,B; ADC A; DAA; MOV B,A
+        0x7b, 0x8f, 0x27, 0x5f, //0112 MOV A,E; ADC A; DAA; MOV E,A
+        0x15,                   //0116 DCR D
+        0xc2, 0x09, 0x01,       //0117 JNZ $0109
+        0x21, 0x80, 0x01,       //011A LXI H,$0180      string buffer
+        0x7b,                   //011D MOV A,E
+        0xcd, 0x3f, 0x01,       //011E CALL $013F       one digit
+        0x78,                   //0121 MOV A,B
+        0xcd, 0x36, 0x01,       //0122 CALL $0136       two digits
+        0x79,                   //0125 MOV A,C
+        0xcd, 0x36, 0x01,       //0126 CALL $0136       two digits
+        0x36, 0x24,             //0129 MVI M,'$'
+        0x11, 0x80, 0x01,       //012B LXI D,$0180
+        0x0e, 0x09,             //012E MVI C,9
+        0xcd, 0x05, 0x00,       //0130 CALL $0005       print the string
+        0xc3, 0x00, 0x00,       //0133 JMP $0000
+        0xf5,                   //0136 PUSH PSW         writes the high digit of A...
+        0x0f, 0x0f, 0x0f, 0x0f, //0137 RRC; RRC; RRC; RRC
+        0xcd, 0x3f, 0x01,       //013B CALL $013F
+        0xf1,                   //013E POP PSW          ...and falls through to the low one
+        0xe6, 0x0f,             //013F ANI $0F          writes the low digit of A at HL
+        0xf6, 0x30,             //0141 ORI '0'
+        0x77,                   //0143 MOV M,A
+        0x23,                   //0144 INX H
+        0xc9,                   //0145 RET
+    ];
+
+    #[test]
+    fn print_hl_in_decimal() {
+        for &(value, expected) in &[
+            (0, "00000"),
+            (9, "00009"),
+            (1234, "01234"),
+            (40960, "40960"),
+            (65535, "65535"),
+        ] {
+            let mut cpu = cpu_with(&[]);
+            cpu.load_program(0x0100, &PRINT_HL);
+            cpu.register.set_hl(value);
+            assert_eq!(cpu.run_cpm_test(), expected);
+        }
+    }
+
+
+    #[test]
+    fn pending_branch_target_at_the_end_of_memory() {
+        let mut cpu = cpu_with(&[0x12, 0x34]);
+        cpu.load_program(0xfffe, &[0xc3, 0x56]);
+        assert_eq!(cpu.pending_branch_target(), Some(0x1256));
+
+        cpu.load_program(0xffff, &[0xcd]);
+        assert_eq!(cpu.pending_branch_target(), Some(0x3412));
+
+        cpu.register.set_hl(0x4000);
+        cpu.load_program(0x0000, &[0xe9]);
+        assert_eq!(cpu.pending_branch_target(), Some(0x4000));
+    }
+
+
+    #[test]
+    fn exec_opcode_add_b() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.a = 0x12;
+        cpu.register.b = 0x34;
+        assert_eq!(cpu.exec_opcode(0x80, &[]), 4);
+        assert_eq!(cpu.register.a, 0x46);
+        assert_eq!(cpu.register.pc, 0);
+    }
+
+    #[test]
+    fn exec_opcode_adi() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.a = 0xf0;
+        assert_eq!(cpu.exec_opcode(0xc6, &[0x20]), 7);
+        assert_eq!(cpu.register.a, 0x10);
+        assert!(cpu.register.get_flag(Flags::Carry));
+        assert_eq!(cpu.register.pc, 0);
+    }
+
+    #[test]
+    fn exec_opcode_jmp() {
+        let mut cpu = cpu_with(&[]);
+        assert_eq!(cpu.exec_opcode(0xc3, &[0x34, 0x12]), 10);
+        assert_eq!(cpu.register.pc, 0x1234);
+    }
+
+
+    #[test]
+    fn is_idle() {
+        //DI; HLT
+        let mut cpu = cpu_with(&[0xf3, 0x76]);
+        cpu.next();
+        assert!(!cpu.is_idle());
+        cpu.next();
+        assert!(cpu.is_idle());
+
+        //EI; JMP $0001
+        let mut cpu = cpu_with(&[0xfb, 0xc3, 0x01, 0x00]);
+        cpu.next();
+        cpu.next();
+        assert!(cpu.is_idle());
+        cpu.interrupt(1);
+        assert_eq!(cpu.register.pc, 0x0008);
+        assert!(!cpu.is_idle());
+    }
+
+
+    #[test]
+    fn pop_at_the_end_of_banked_memory() {
+        let memory = Rc::new(RefCell::new(BankedMemory::new(0x8000..0x10000, 2)));
+        let mut cpu = Cpu::new(memory.clone());
+        //POP B, the high byte is read from 0x0000
+        cpu.load_program(0, &[0xc1]);
+        memory.borrow_mut().set(0xffff, 0x34);
+        cpu.register.sp = 0xffff;
+        cpu.next();
+        assert_eq!(cpu.register.get_bc(), 0xc134);
+        assert_eq!(cpu.register.sp, 0x0001);
+    }
+
+
+    #[test]
+    fn call_pushes_the_address_after_it() {
+        //LXI SP,$0100; CALL $0010; HLT; ...; RET at $0010
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x01, 0xcd, 0x10, 0x00, 0x76]);
+        cpu.load_program(0x0010, &[0xc9]);
+        cpu.register.pc = 0;
+        cpu.next();
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x0010);
+        assert_eq!(cpu.register.sp, 0x00fe);
+        assert_eq!(cpu.peek_return_address(), 0x0006);
+
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x0006);
+        assert_eq!(cpu.register.sp, 0x0100);
+    }
+
+    #[test]
+    fn untaken_call_pushes_nothing() {
+        //LXI SP,$0100; CNZ $0010 with Zero set
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x01, 0xc4, 0x10, 0x00]);
+        cpu.register.set_flag(Flags::Zero, true);
+        cpu.next();
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x0006);
+        assert_eq!(cpu.register.sp, 0x0100);
+    }
+
+
+    #[test]
+    fn step_force_runs_past_hlt() {
+        //HLT; MVI A,$42
+        let mut cpu = cpu_with(&[0x76, 0x3e, 0x42]);
+        cpu.next();
+        assert!(cpu.is_halted());
+        let total = cpu.total_cycles();
+
+        assert_eq!(cpu.step_force(), 7);
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.register.a, 0x42);
+        assert_eq!(cpu.register.pc, 0x0003);
+        assert_eq!(cpu.total_cycles(), total + 7);
+    }
+
+
+    #[test]
+    fn run_a_program_loaded_from_hex() {
+        let mut memory = Linear::new();
+        memory.load_hex_string(0, "3e 05 06 03 80 76").unwrap();
+        let mut cpu = Cpu::new(Rc::new(RefCell::new(memory)));
+        cpu.run();
+        assert_eq!(cpu.register.a, 0x08);
+    }
+
+
+    #[test]
+    fn shld_and_lhld() {
+        //SHLD $2000; LXI H,$0000; LHLD $2000
+        let mut cpu = cpu_with(&[0x22, 0x00, 0x20, 0x21, 0x00, 0x00, 0x2a, 0x00, 0x20]);
+        cpu.register.set_hl(0x1234);
+        assert_eq!(cpu.next(), 16);
+        assert_eq!(cpu.memory.borrow().get(0x2000), 0x34);
+        assert_eq!(cpu.memory.borrow().get(0x2001), 0x12);
+
+        cpu.next();
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert_eq!(cpu.next(), 16);
+        assert_eq!(cpu.register.get_hl(), 0x1234);
+    }
+
+
+    #[test]
+    fn mvi_and_hlt_are_fetched_from_memory() {
+        let mut cpu = cpu_with(&[0x3e, 0x42, 0x76]);
+        cpu.next();
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x42);
+        assert!(cpu.stop);
+    }
+
+
+    #[test]
+    fn cycles_of_call() {
+        let mut cpu = cpu_with(&[0x00, 0xcd, 0x10, 0x00]);
+        cpu.register.sp = 0x0100;
+        assert_eq!(cpu.next(), 4);
+        assert_eq!(cpu.next(), 17);
+
+        //CZ $0020
+        cpu.load_program(0x0010, &[0xcc, 0x20, 0x00]);
+        cpu.register.set_flag(Flags::Zero, false);
+        assert_eq!(cpu.next(), 11);
+        assert_eq!(cpu.register.pc, 0x0013);
+
+        cpu.register.pc = 0x0010;
+        cpu.register.set_flag(Flags::Zero, true);
+        assert_eq!(cpu.next(), 17);
+        assert_eq!(cpu.register.pc, 0x0020);
+    }
+
+
+    //Flags expected after an 8 bit addition or subtraction, computed without the alu.
+    //The subtraction is done as a + !b + 1, so its AC is the carry out of bit 3
+    //and its Carry is the missing carry out of bit 7
+    fn reference_flags(result: u8, carry: bool, aux_carry: bool) -> u8 {
+        let mut f = 0x02;
+        if result & 0x80 != 0 { f |= 0x80; }
+        if result == 0 { f |= 0x40; }
+        if aux_carry { f |= 0x10; }
+        if result.count_ones() & 1 == 0 { f |= 0x04; }
+        if carry { f |= 0x01; }
+        f
+    }
+
+    //ADD B (0x80) and SUB B (0x90) for every pair of operands
+    #[test]
+    fn add_and_sub_flags_match_the_reference() {
+        let mut cpu = cpu_with(&[]);
+        for a in 0..=255u8 {
+            for b in 0..=255u8 {
+                cpu.register.f = 0x02;
+                cpu.register.a = a;
+                cpu.register.b = b;
+                cpu.exec_opcode(0x80, &[]);
+                let sum = u16::from(a) + u16::from(b);
+                let aux_carry = (a & 0x0f) + (b & 0x0f) > 0x0f;
+                assert_eq!(cpu.register.a, sum as u8, "ADD {:02x} {:02x}", a, b);
+                assert_eq!(cpu.register.f, reference_flags(sum as u8, sum > 0xff, aux_carry), "ADD {:02x} {:02x}", a, b);
+
+                cpu.register.f = 0x02;
+                cpu.register.a = a;
+                cpu.exec_opcode(0x90, &[]);
+                let sum = u16::from(a) + u16::from(!b) + 1;
+                let aux_carry = (a & 0x0f) + (!b & 0x0f) + 1 > 0x0f;
+                assert_eq!(cpu.register.a, sum as u8, "SUB {:02x} {:02x}", a, b);
+                assert_eq!(cpu.register.f, reference_flags(sum as u8, sum <= 0xff, aux_carry), "SUB {:02x} {:02x}", a, b);
+            }
+        }
+    }
+
+    //INR A (0x3c) and DCR A (0x3d) for every operand, with the Carry set and clear
+    #[test]
+    fn inr_and_dcr_flags_match_the_reference() {
+        let mut cpu = cpu_with(&[]);
+        for a in 0..=255u8 {
+            for &carry in &[false, true] {
+                cpu.register.f = if carry { 0x03 } else { 0x02 };
+                cpu.register.a = a;
+                cpu.exec_opcode(0x3c, &[]);
+                let result = a.wrapping_add(1);
+                let aux_carry = (a & 0x0f) + 1 > 0x0f;
+                assert_eq!(cpu.register.a, result, "INR {:02x}", a);
+                assert_eq!(cpu.register.f, reference_flags(result, carry, aux_carry), "INR {:02x}", a);
+
+                cpu.register.f = if carry { 0x03 } else { 0x02 };
+                cpu.register.a = a;
+                cpu.exec_opcode(0x3d, &[]);
+                let result = a.wrapping_add(0xff);
+                let aux_carry = (a & 0x0f) + 0x0f > 0x0f;
+                assert_eq!(cpu.register.a, result, "DCR {:02x}", a);
+                assert_eq!(cpu.register.f, reference_flags(result, carry, aux_carry), "DCR {:02x}", a);
+            }
+        }
+    }
+
+
+    #[test]
+    fn enabled_interrupt_jumps_to_the_vector() {
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        cpu.register.sp = 0x0100;
+        cpu.next();
+        cpu.next();
+        cpu.interrupt(2);
+        assert_eq!(cpu.register.pc, 0x0010);
+        assert_eq!(cpu.peek_return_address(), 0x0002);
+        assert!(!cpu.interrupt_flipflop());
+
+        cpu.interrupt(1);
+        assert_eq!(cpu.register.pc, 0x0010);
+    }
+
+    #[test]
+    fn disabled_interrupt_is_ignored() {
+        let mut cpu = cpu_with(&[0x00]);
+        cpu.register.sp = 0x0100;
+        cpu.interrupt(2);
+        assert_eq!(cpu.register.pc, 0x0000);
+        assert_eq!(cpu.register.sp, 0x0100);
+    }
+
+
+    #[test]
+    fn interrupt_wakes_a_halted_cpu() {
+        //EI; HLT
+        let mut cpu = cpu_with(&[0xfb, 0x76]);
+        cpu.register.sp = 0x0100;
+        cpu.next();
+        cpu.next();
+        assert!(cpu.stop);
+        for _ in 0..3 {
+            assert_eq!(cpu.next(), 4);
+            assert_eq!(cpu.register.pc, 0x0002);
+        }
+
+        cpu.interrupt(1);
+        assert!(!cpu.stop);
+        assert_eq!(cpu.register.pc, 0x0008);
+        assert_eq!(cpu.peek_return_address(), 0x0002);
+    }
+
+
+    //Runs opcode with operands from 0x0000 and returns the registers it left
+    fn run_alias(opcode: u8, operands: &[u8]) -> Register {
+        let mut program = vec![opcode];
+        program.extend_from_slice(operands);
+        let mut cpu = cpu_with(&program);
+        cpu.register.sp = 0x0100;
+        cpu.memory.borrow_mut().set_word(0x0100, 0x1234);
+        cpu.next();
+        cpu.register
+    }
+
+    #[test]
+    fn aliases_behave_like_the_documented_opcodes() {
+        let nop = run_alias(0x00, &[]);
+        for &opcode in &[0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38] {
+            assert_eq!(run_alias(opcode, &[]).values(), nop.values(), "{:02x}", opcode);
+        }
+
+        assert_eq!(run_alias(0xcb, &[0x34, 0x12]).values(), run_alias(0xc3, &[0x34, 0x12]).values());
+        assert_eq!(run_alias(0xd9, &[]).values(), run_alias(0xc9, &[]).values());
+        let call = run_alias(0xcd, &[0x00, 0x20]);
+        assert_eq!(call.pc, 0x2000);
+        for &opcode in &[0xdd, 0xed, 0xfd] {
+            assert_eq!(run_alias(opcode, &[0x00, 0x20]).values(), call.values(), "{:02x}", opcode);
+        }
+    }
+
+
+    #[test]
+    fn inr_m_and_dcr_m() {
+        //INR M; DCR M; INR M
+        let mut cpu = cpu_with(&[0x34, 0x35, 0x34]);
+        cpu.register.set_hl(0x2000);
+        cpu.memory.borrow_mut().set(0x2000, 0x0f);
+        cpu.register.set_flag(Flags::Carry, true);
+
+        assert_eq!(cpu.next(), 10);
+        assert_eq!(cpu.memory.borrow().get(0x2000), 0x10);
+        assert!(cpu.register.get_flag(Flags::AC));
+        assert!(!cpu.register.get_flag(Flags::Zero));
+        assert!(!cpu.register.get_flag(Flags::Sign));
+        assert!(!cpu.register.get_flag(Flags::Parity));
+        assert!(cpu.register.get_flag(Flags::Carry));
+
+        assert_eq!(cpu.next(), 10);
+        assert_eq!(cpu.memory.borrow().get(0x2000), 0x0f);
+        assert!(!cpu.register.get_flag(Flags::AC));
+        assert!(cpu.register.get_flag(Flags::Parity));
+        assert!(cpu.register.get_flag(Flags::Carry));
+
+        cpu.memory.borrow_mut().set(0x2000, 0xff);
+        cpu.register.set_flag(Flags::Carry, false);
+        cpu.next();
+        assert_eq!(cpu.memory.borrow().get(0x2000), 0x00);
+        assert!(cpu.register.get_flag(Flags::Zero));
+        assert!(cpu.register.get_flag(Flags::AC));
+        assert!(!cpu.register.get_flag(Flags::Carry));
+    }
+
+
+    #[test]
+    fn executing_from_the_stack_is_reported() {
+        //JMP $F000
+        let mut cpu = cpu_with(&[0xc3, 0x00, 0xf0]);
+        cpu.set_stack_region(0xf000, 0xffff);
+        cpu.next();
+        assert!(cpu.events().is_empty());
+
+        cpu.next();
+        assert_eq!(cpu.events(), &[Event::ExecFromStack { addr: 0xf000 }]);
+        cpu.clear_events();
+        assert!(cpu.events().is_empty());
+    }
+
+
+    #[test]
+    fn cycle_attribution_of_a_loop() {
+        //MVI B,3; DCR B; JNZ $0002; HLT
+        let mut cpu = cpu_with(&[0x06, 0x03, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+        cpu.enable_cycle_attribution();
+        cpu.run();
+
+        let attribution = cpu.cycle_attribution();
+        assert_eq!(attribution.len(), 4);
+        assert_eq!(attribution[&0x0000], 7);
+        assert_eq!(attribution[&0x0002], 15);
+        assert_eq!(attribution[&0x0003], 30);
+        assert_eq!(attribution[&0x0006], 7);
+    }
+
+
+    //ANA B (0xa0), XRA B (0xa8) and ORA B (0xb0) for every pair of operands: the Carry is
+    //always cleared, ANA sets AC to bit 3 of a | b and XRA/ORA clear it
+    #[test]
+    fn logical_flags_match_the_reference() {
+        let mut cpu = cpu_with(&[]);
+        for a in 0..=255u8 {
+            for b in 0..=255u8 {
+                let cases = [
+                    (0xa0, a & b, (a | b) & 0x08 != 0),
+                    (0xa8, a ^ b, false),
+                    (0xb0, a | b, false),
+                ];
+                for &(opcode, result, aux_carry) in cases.iter() {
+                    cpu.register.f = 0x13;
+                    cpu.register.a = a;
+                    cpu.register.b = b;
+                    cpu.exec_opcode(opcode, &[]);
+                    assert_eq!(cpu.register.a, result, "{:02x} {:02x} {:02x}", opcode, a, b);
+                    assert_eq!(cpu.register.f, reference_flags(result, false, aux_carry), "{:02x} {:02x} {:02x}", opcode, a, b);
+                }
+            }
+        }
+    }
+
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_records() {
+        use std::sync::Once;
+        use log::{Level, Log, Metadata, Record};
+
+        //The tests run in parallel, so every thread keeps its own records
+        thread_local! {
+            static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct CapturingLogger;
+
+        impl Log for CapturingLogger {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                RECORDS.with(|records| {
+                    records.borrow_mut().push((record.level(), record.args().to_string()))
+                });
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger;
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+
+        //MVI A,$05; EI; HLT
+        let mut cpu = cpu_with(&[0x3e, 0x05, 0xfb, 0x76]);
+        cpu.register.sp = 0x0100;
+        cpu.run();
+        cpu.interrupt(1);
+
+        let records = RECORDS.with(|records| records.borrow().clone());
+        let traces: Vec<&String> = records
+            .iter()
+            .filter(|(level, _)| *level == Level::Trace)
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(traces.len(), 6);
+        assert!(traces[0].starts_with("IN  003e MVI A"));
+        assert!(traces[0].contains("PC=0000"));
+        assert!(traces[1].starts_with("OUT 003e MVI A"));
+        assert!(traces[1].contains("A=05"));
+        assert!(traces[4].starts_with("IN  0076 HLT"));
+
+        let debugs: Vec<&String> = records
+            .iter()
+            .filter(|(level, _)| *level == Level::Debug)
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(debugs, ["Halted at 0003", "Interrupt at 0004, jumping to 0008"]);
+    }
+
+
+    #[test]
+    fn restore_repeats_the_same_execution() {
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0100, &PRINT_HL);
+        cpu.register.set_hl(1234);
+        cpu.run_steps(50);
+
+        let state = cpu.snapshot();
+        cpu.run_steps(100);
+        let first = cpu.snapshot();
+
+        cpu.restore(&state);
+        cpu.run_steps(100);
+        let second = cpu.snapshot();
+
+        assert_eq!(first.register.values(), second.register.values());
+        assert_eq!(first.total_cycles, second.total_cycles);
+        assert_eq!(first.memory, second.memory);
+        assert_ne!(state.register.values(), first.register.values());
+    }
+
+    #[test]
+    fn snapshot_keeps_the_injected_bytes() {
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        cpu.register.sp = 0x0100;
+        cpu.next();
+        cpu.next();
+        cpu.interrupt_with_opcode(0xcd, &[0x00, 0x20]);
+        let state = cpu.snapshot();
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x2000);
+
+        cpu.restore(&state);
+        assert_eq!(cpu.register.pc, 0x0002);
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x2000);
+        assert_eq!(cpu.peek_return_address(), 0x0002);
+    }
+
+    #[test]
+    fn snapshot_keeps_every_bank() {
+        let memory = Rc::new(RefCell::new(BankedMemory::new(0x8000..0x10000, 2)));
+        let mut cpu = Cpu::new(memory.clone());
+        memory.borrow_mut().set(0x8000, 0xaa);
+        memory.borrow_mut().select_bank(1);
+        memory.borrow_mut().set(0x8000, 0xbb);
+        let state = cpu.snapshot();
+
+        memory.borrow_mut().set(0x8000, 0x00);
+        memory.borrow_mut().select_bank(0);
+        memory.borrow_mut().set(0x8000, 0x00);
+        cpu.restore(&state);
+
+        assert_eq!(memory.borrow().active_bank(), 1);
+        assert_eq!(memory.borrow().get(0x8000), 0xbb);
+        memory.borrow_mut().select_bank(0);
+        assert_eq!(memory.borrow().get(0x8000), 0xaa);
+    }
+
+
+    #[test]
+    fn dad_carry() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.set_hl(0x8000);
+        cpu.register.set_bc(0x8000);
+        assert_eq!(cpu.exec_opcode(0x09, &[]), 10);
+        assert_eq!(cpu.register.get_hl(), 0x0000);
+        assert!(cpu.register.get_flag(Flags::Carry));
+
+        cpu.register.set_hl(0x0001);
+        cpu.register.set_bc(0x0001);
+        cpu.exec_opcode(0x09, &[]);
+        assert_eq!(cpu.register.get_hl(), 0x0002);
+        assert!(!cpu.register.get_flag(Flags::Carry));
+
+        //DAD H
+        cpu.register.set_hl(0x8001);
+        cpu.exec_opcode(0x29, &[]);
+        assert_eq!(cpu.register.get_hl(), 0x0002);
+        assert!(cpu.register.get_flag(Flags::Carry));
+    }
+
+
+    #[test]
+    fn conditional_returns() {
+        let cases = [
+            (0xc0, Flags::Zero, false),
+            (0xc8, Flags::Zero, true),
+            (0xd0, Flags::Carry, false),
+            (0xd8, Flags::Carry, true),
+            (0xe0, Flags::Parity, false),
+            (0xe8, Flags::Parity, true),
+            (0xf0, Flags::Sign, false),
+            (0xf8, Flags::Sign, true),
+        ];
+        for &(opcode, flag, taken_when) in cases.iter() {
+            for &taken in &[true, false] {
+                let mut cpu = cpu_with(&[opcode]);
+                cpu.register.sp = 0x0100;
+                cpu.memory.borrow_mut().set_word(0x0100, 0x1234);
+                cpu.register.set_flag(flag, taken_when == taken);
+
+                if taken {
+                    assert_eq!(cpu.next(), 11, "{:02x}", opcode);
+                    assert_eq!(cpu.register.pc, 0x1234, "{:02x}", opcode);
+                    assert_eq!(cpu.register.sp, 0x0102, "{:02x}", opcode);
+                }
+                else {
+                    assert_eq!(cpu.next(), 5, "{:02x}", opcode);
+                    assert_eq!(cpu.register.pc, 0x0001, "{:02x}", opcode);
+                    assert_eq!(cpu.register.sp, 0x0100, "{:02x}", opcode);
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn step_traced() {
+        //MVI A,$05; INR A
+        let mut cpu = cpu_with(&[0x3e, 0x05, 0x3c]);
+        let trace = cpu.step_traced();
+        assert_eq!(trace.pc, 0x0000);
+        assert_eq!(trace.mnemonic, "MVI A,$05");
+        assert_eq!(trace.bytes, [0x3e, 0x05]);
+        assert_eq!(trace.register.a, 0x05);
+
+        let trace = cpu.step_traced();
+        assert_eq!(trace.pc, 0x0002);
+        assert_eq!(trace.mnemonic, "INR A");
+        assert_eq!(trace.bytes, [0x3c]);
+        assert_eq!(trace.register.a, 0x06);
+    }
+
+
+    #[test]
+    fn push_and_pop_psw() {
+        //PUSH PSW; XRA A; POP PSW
+        let mut cpu = cpu_with(&[0xf5, 0xaf, 0xf1]);
+        cpu.register.sp = 0x0100;
+        cpu.register.a = 0x5a;
+        cpu.register.set_flag(Flags::Sign, true);
+        cpu.register.set_flag(Flags::AC, true);
+        cpu.register.set_flag(Flags::Carry, true);
+        let f = cpu.register.f;
+
+        cpu.next();
+        let low = cpu.memory.borrow().get(0x00fe);
+        assert_eq!(low & 0x2a, 0x02);
+        cpu.next();
+        assert_ne!(cpu.register.f, f);
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x5a);
+        assert_eq!(cpu.register.f, f);
+
+        //POP PSW of a byte with every bit set
+        cpu.memory.borrow_mut().set_word(0x00fe, 0x12ff);
+        cpu.register.sp = 0x00fe;
+        cpu.exec_opcode(0xf1, &[]);
+        assert_eq!(cpu.register.a, 0x12);
+        assert_eq!(cpu.register.f, 0xd7);
+    }
+
+
+    #[test]
+    fn injected_call_is_what_the_helpers_see() {
+        //EI; NOP; ...; RET at $0020, the PC is in the stack region
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        cpu.load_program(0x0020, &[0xc9]);
+        cpu.register.pc = 0;
+        cpu.register.sp = 0x0100;
+        cpu.next();
+        cpu.next();
+        cpu.set_stack_region(0x0000, 0x00ff);
+        cpu.enable_ring_trace(4);
+
+        cpu.interrupt_with_opcode(0xcd, &[0x20, 0x00]);
+        assert!(cpu.trace_line().starts_with("00cd CALL"));
+        assert_eq!(cpu.pending_branch_target(), Some(0x0020));
+        assert_eq!(cpu.fallthrough_pc(), 0x0002);
+        let trace = cpu.step_traced();
+        assert_eq!(trace.pc, 0x0002);
+        assert_eq!(trace.mnemonic, "CALL $0020");
+        assert_eq!(trace.bytes, [0xcd, 0x20, 0x00]);
+        assert_eq!(cpu.register.pc, 0x0020);
+        assert_eq!(cpu.peek_return_address(), 0x0002);
+        assert!(cpu.ring_trace()[0].starts_with("00cd CALL"));
+        assert!(cpu.events().is_empty());
+
+        cpu.next();
+        assert_eq!(cpu.events(), &[Event::ExecFromStack { addr: 0x0020 }]);
+    }
+
+    #[test]
+    fn step_over_an_injected_call() {
+        //EI; NOP; ...; MVI A,$42; EI; RET at $0020
+        let mut cpu = cpu_with(&[0xfb, 0x00, 0x00]);
+        cpu.load_program(0x0020, &[0x3e, 0x42, 0xfb, 0xc9]);
+        cpu.register.pc = 0;
+        cpu.register.sp = 0x0100;
+        cpu.next();
+        cpu.next();
+
+        cpu.interrupt_with_opcode(0xcd, &[0x20, 0x00]);
+        assert_eq!(cpu.step_over(10), RunOutcome::Completed);
+        assert_eq!(cpu.register.pc, 0x0002);
+        assert_eq!(cpu.register.sp, 0x0100);
+        assert_eq!(cpu.register.a, 0x42);
+
+        cpu.interrupt_with_opcode(0xcd, &[0x20, 0x00]);
+        let step = cpu.step_verbose();
+        assert_eq!(step.addr, 0x0002);
+        assert_eq!(step.mnemonic, "CALL");
+        assert_eq!(cpu.register.pc, 0x0020);
+    }
+
+
+    #[test]
+    fn run_cpm_test_prints_a_string() {
+        //LXI D,$0110; MVI C,9; CALL $0005; JMP $0000; ...; "OK$" at $0110
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0110, b"OK$");
+        cpu.load_program(0x0100, &[0x11, 0x10, 0x01, 0x0e, 0x09, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00]);
+        cpu.register.sp = 0xf000;
+        assert_eq!(cpu.run_cpm_test(), "OK");
+    }
+
+    #[test]
+    fn run_cpm_test_stops_on_a_string_without_end() {
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0100, &[0x11, 0x10, 0x01, 0x0e, 0x09, 0xcd, 0x05, 0x00, 0xc3, 0x00, 0x00]);
+        cpu.register.sp = 0xf000;
+        let output = cpu.run_cpm_test();
+        assert!(output.ends_with("\nBDOS 9: the string at 0110 has no '$'\n"));
+        assert_eq!(output.chars().count(), 0x10000 + 39);
+    }
+
+    #[test]
+    fn run_cpm_test_with_other_addresses() {
+        //MVI C,2; MVI E,'!'; CALL $F000; JMP $FF00
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0100, &[0x0e, 0x02, 0x1e, b'!', 0xcd, 0x00, 0xf0, 0xc3, 0x00, 0xff]);
+        cpu.register.sp = 0xe000;
+        let config = CpmConfig { exit: 0xff00, bdos: 0xf000 };
+        assert_eq!(cpu.run_cpm_test_with(config), "!");
+        assert_eq!(cpu.register.pc, 0xff00);
+        assert_eq!(cpu.register.sp, 0xe000);
+    }
+
+
+    #[test]
+    fn cmp_leaves_a_untouched() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.a = 0x0a;
+        cpu.register.b = 0x05;
+        cpu.exec_opcode(0xb8, &[]);
+        assert_eq!(cpu.register.a, 0x0a);
+        assert!(!cpu.register.get_flag(Flags::Carry));
+        assert!(!cpu.register.get_flag(Flags::Zero));
+
+        cpu.register.a = 0x05;
+        cpu.register.b = 0x0a;
+        cpu.exec_opcode(0xb8, &[]);
+        assert_eq!(cpu.register.a, 0x05);
+        assert!(cpu.register.get_flag(Flags::Carry));
+        assert!(!cpu.register.get_flag(Flags::Zero));
+    }
+
+    #[test]
+    fn cmp_a_compares_a_with_itself() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.a = 0x42;
+        cpu.register.set_flag(Flags::Carry, true);
+        cpu.exec_opcode(0xbf, &[]);
+        assert_eq!(cpu.register.a, 0x42);
+        assert!(cpu.register.get_flag(Flags::Zero));
+        assert!(!cpu.register.get_flag(Flags::Carry));
+    }
+
+
+    #[test]
+    fn rotates() {
+        //(opcode, carry before, A after, carry after) starting from A=0xAA
+        let cases = [
+            (0x07, false, 0x55, true),      //RLC
+            (0x0f, false, 0x55, false),     //RRC
+            (0x17, false, 0x54, true),      //RAL
+            (0x17, true, 0x55, true),
+            (0x1f, false, 0x55, false),     //RAR
+            (0x1f, true, 0xd5, false),
+        ];
+        let mut cpu = cpu_with(&[]);
+        for &(opcode, carry, a, carry_after) in cases.iter() {
+            cpu.register.a = 0xaa;
+            cpu.register.set_flag(Flags::Carry, carry);
+            assert_eq!(cpu.exec_opcode(opcode, &[]), 4);
+            assert_eq!(cpu.register.a, a, "{:02x} carry {}", opcode, carry);
+            assert_eq!(cpu.register.get_flag(Flags::Carry), carry_after, "{:02x} carry {}", opcode, carry);
+        }
+    }
+
+
+    #[test]
+    fn cycles_of_cnz_and_ret() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.sp = 0x0100;
+        cpu.register.set_flag(Flags::Zero, true);
+        assert_eq!(cpu.exec_opcode(0xc4, &[0x00, 0x20]), 11);
+        assert_eq!(cpu.register.sp, 0x0100);
+
+        cpu.register.set_flag(Flags::Zero, false);
+        assert_eq!(cpu.exec_opcode(0xc4, &[0x00, 0x20]), 17);
+        assert_eq!(cpu.register.pc, 0x2000);
+
+        assert_eq!(cpu.exec_opcode(0xc9, &[]), 10);
+        assert_eq!(cpu.register.pc, 0x0000);
+        cpu.register.sp = 0x00fe;
+        assert_eq!(cpu.exec_opcode(0xd9, &[]), 10);
+        assert_eq!(cpu.register.sp, 0x0100);
+    }
+
+
+    #[test]
+    fn breakpoint_and_watchpoint_in_a_loop() {
+        //MVI B,3; DCR B; STA $3000; JNZ $0002; HLT
+        let mut cpu = cpu_with(&[0x06, 0x03, 0x05, 0x32, 0x00, 0x30, 0xc2, 0x02, 0x00, 0x76]);
+        cpu.add_breakpoint(0x0002);
+        cpu.add_watchpoint(0x3000);
+
+        let mut hits = Vec::new();
+        while !cpu.is_halted() {
+            if let Some(hit) = cpu.step_debug() {
+                hits.push(hit);
+            }
+        }
+        assert_eq!(hits, [
+            DebugHit::Pc(0x0002), DebugHit::Write(0x3000),
+            DebugHit::Pc(0x0002), DebugHit::Write(0x3000),
+            DebugHit::Pc(0x0002), DebugHit::Write(0x3000),
+        ]);
+
+        cpu.remove_breakpoint(0x0002);
+        cpu.remove_watchpoint(0x3000);
+        cpu.register.pc = 0;
+        cpu.stop = false;
+        while !cpu.is_halted() {
+            assert_eq!(cpu.step_debug(), None);
+        }
+    }
+
+
+    #[test]
+    fn every_mov() {
+        for opcode in 0x40..=0x7fu8 {
+            let mut cpu = cpu_with(&[]);
+            if opcode == 0x76 {
+                cpu.exec_opcode(opcode, &[]);
+                assert!(cpu.is_halted());
+                continue;
+            }
+
+            for (idx, &reg) in [Reg::B, Reg::C, Reg::D, Reg::E, Reg::A].iter().enumerate() {
+                cpu.set_reg(reg, 0x11 * (idx as u8 + 1));
+            }
+            cpu.register.set_hl(0x2010);
+            cpu.memory.borrow_mut().set(0x2010, 0x99);
+
+            let dst = Reg::from_code(opcode >> 3);
+            let src = Reg::from_code(opcode);
+            let value = cpu.get_reg(src);
+            let cycles = if dst == Reg::M || src == Reg::M { 7 } else { 5 };
+            assert_eq!(cpu.exec_opcode(opcode, &[]), cycles, "{:02x}", opcode);
+            assert_eq!(cpu.get_reg(dst), value, "{:02x}", opcode);
+            assert!(!cpu.is_halted());
+        }
+    }
+
+
+    #[test]
+    fn daa_edge_cases() {
+        let mut cpu = cpu_with(&[]);
+        cpu.register.a = 0x9a;
+        cpu.register.set_flag(Flags::AC, false);
+        cpu.register.set_flag(Flags::Carry, false);
+        cpu.exec_opcode(0x27, &[]);
+        assert_eq!(cpu.register.a, 0x00);
+        assert!(cpu.register.get_flag(Flags::Carry));
+        assert!(cpu.register.get_flag(Flags::Zero));
+
+        cpu.register.a = 0x0f;
+        cpu.register.set_flag(Flags::AC, true);
+        cpu.register.set_flag(Flags::Carry, false);
+        cpu.exec_opcode(0x27, &[]);
+        assert_eq!(cpu.register.a, 0x15);
+        assert!(!cpu.register.get_flag(Flags::Carry));
+
+        //MVI A,$88; ADI $88; DAA
+        let mut cpu = cpu_with(&[0x3e, 0x88, 0xc6, 0x88, 0x27]);
+        cpu.run_steps(3);
+        assert_eq!(cpu.register.a, 0x76);
+        assert!(cpu.register.get_flag(Flags::Carry));
+    }
+
+
+    #[test]
+    fn instruction_limit_stops_a_jmp_to_itself() {
+        //DI; JMP $0001
+        let mut cpu = cpu_with(&[0xf3, 0xc3, 0x01, 0x00]);
+        cpu.set_instruction_limit(Some(100));
+        assert_eq!(cpu.run(), RunOutcome::InstructionLimit);
+        assert!(cpu.instruction_limit_reached());
+        assert_eq!(cpu.total_cycles(), 4 + 99 * 10);
+
+        assert_eq!(cpu.run_steps(10), 0);
+        assert_eq!(cpu.run_until_instant(Instant::now() + std::time::Duration::from_secs(60)), 0);
+        assert_eq!(cpu.run_frame(1000).len(), VIDEO_RAM.len());
+        assert_eq!(cpu.total_cycles(), 4 + 99 * 10);
+        assert!(cpu.run_cpm_test().ends_with("instruction limit reached\n"));
+
+        cpu.set_instruction_limit(None);
+        assert_eq!(cpu.run_steps(10), 10);
+    }
+
+
+    //MVI A,$3A; MVI B,$C6; ADD B: A becomes 0 with Zero, AC, Parity and Carry set
+    const KNOWN_ADD: [u8; 5] = [0x3e, 0x3a, 0x06, 0xc6, 0x80];
+
+    #[test]
+    fn state_table_after_add() {
+        let mut cpu = cpu_with(&KNOWN_ADD);
+        cpu.run_steps(3);
+        assert_eq!(
+            cpu.state_table(),
+            "A  F  B  C  D  E  H  L  SP   PC   S Z A P C\n\
+             00 57 c6 00 00 00 00 00 0000 0005 0 1 1 1 1"
+        );
+    }
+
+
+    #[test]
+    fn run_frame_defers_the_interrupts_until_ei() {
+        //HLT at $0008 and $0010; DI, NOPs and EI at $005F from $0040
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0008, &[0x76]);
+        cpu.load_program(0x0010, &[0x76]);
+        cpu.load_program(0x005f, &[0xfb]);
+        cpu.load_program(0x0040, &[0xf3]);
+        cpu.register.sp = 0x0100;
+
+        //DI and 24 NOPs fill the frame, both RSTs are requested under DI
+        cpu.run_frame(100);
+        assert_eq!(cpu.register.pc, 0x0059);
+        assert_eq!(cpu.register.sp, 0x0100);
+
+        //6 NOPs, EI and the NOP after it, then RST 1 is delivered
+        cpu.run_frame(100);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.register.pc, 0x0009);
+        assert_eq!(cpu.peek_return_address(), 0x0061);
+        assert_eq!(cpu.snapshot().deferred_interrupts, [2, 1]);
+    }
+
+
+
+    #[test]
+    fn reset_jumps_to_the_reset_vector() {
+        //MVI A,$42 at $0100
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0100, &[0x3e, 0x42]);
+        cpu.set_reset_vector(0x0100);
+        cpu.register.pc = 0x1234;
+
+        cpu.reset();
+        assert_eq!(cpu.register.pc, 0x0100);
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x42);
+        assert_eq!(cpu.register.pc, 0x0102);
+
+        cpu.reset();
+        assert_eq!(cpu.register.pc, 0x0100);
+        assert_eq!(cpu.register.a, 0x00);
+    }
+
+
+
+    #[test]
+    fn pc_wraps_at_the_end_of_memory() {
+        //NOP at $FFFF
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0xffff, &[0x00]);
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x0000);
+
+        //LXI B,$1234 at $FFFE: the high byte is read from $0000
+        let mut cpu = cpu_with(&[0x12]);
+        cpu.load_program(0xfffe, &[0x01, 0x34]);
+        cpu.next();
+        assert_eq!(cpu.register.get_bc(), 0x1234);
+        assert_eq!(cpu.register.pc, 0x0001);
+    }
+
+
+
+    #[test]
+    fn snapshot_keeps_the_devices() {
+        //MVI A,$03; OUT $02; MVI A,$AB; OUT $04; MVI A,$CD; OUT $04; IN $03
+        let mut cpu = cpu_with(&[
+            0x3e, 0x03, 0xd3, 0x02, 0x3e, 0xab, 0xd3, 0x04, 0x3e, 0xcd, 0xd3, 0x04, 0xdb, 0x03,
+        ]);
+        let shift = Rc::new(RefCell::new(super::super::invaders::ShiftRegister::default()));
+        cpu.io = shift.clone();
+        cpu.run_steps(6);
+        let state = cpu.snapshot();
+        assert_eq!(state.io, [0xab, 0xcd, 0x03]);
+
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x6d);
+        shift.borrow_mut().value = 0x0000;
+        shift.borrow_mut().offset = 0;
+
+        cpu.restore(&state);
+        assert_eq!(shift.borrow().value, 0xcdab);
+        assert_eq!(shift.borrow().offset, 3);
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x6d);
+    }
+
+
+
+    #[test]
+    fn branch_profile_of_a_loop() {
+        //MVI B,$03; DCR B; JNZ $0002; HLT
+        let mut cpu = cpu_with(&[0x06, 0x03, 0x05, 0xc2, 0x02, 0x00, 0x76]);
+        assert!(cpu.branch_profile().is_empty());
+        cpu.enable_branch_profile();
+        assert_eq!(cpu.run(), RunOutcome::Halted);
+
+        let profile = cpu.branch_profile();
+        assert_eq!(profile.len(), 1);
+        assert_eq!(profile[&0x0003], (2, 1));
+
+        cpu.enable_branch_profile();
+        assert!(cpu.branch_profile().is_empty());
+    }
+
+
+
+    #[test]
+    fn pre_exec_skips_the_vetoed_instruction() {
+        //INR A; INR B; INR A
+        let mut cpu = cpu_with(&[0x3c, 0x04, 0x3c]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let recorded = seen.clone();
+        cpu.set_pre_exec(move |cpu, opcode| {
+            recorded.borrow_mut().push((cpu.register.pc, opcode));
+            opcode != 0x3c
+        });
+
+        assert_eq!(cpu.next(), 0);
+        assert_eq!(cpu.register.a, 0x00);
+        assert_eq!(cpu.register.pc, 0x0001);
+
+        assert_eq!(cpu.next(), 5);
+        assert_eq!(cpu.register.b, 0x01);
+        assert_eq!(cpu.register.pc, 0x0002);
+
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x00);
+        assert_eq!(cpu.register.pc, 0x0003);
+        assert_eq!(*seen.borrow(), [(0x0001, 0x3c), (0x0002, 0x04), (0x0003, 0x3c)]);
+    }
+
+
+
+    #[test]
+    fn disassemble_around_pc() {
+        //MVI A,$05; LXI B,$1234; NOP; JMP $0000; HLT
+        let mut cpu = cpu_with(&[0x3e, 0x05, 0x01, 0x34, 0x12, 0x00, 0xc3, 0x00, 0x00, 0x76]);
+        cpu.register.pc = 0x0005;
+        assert_eq!(
+            cpu.disassemble_around(2, 2),
+            "  0000 MVI A,$05\n  0002 LXI BC,$1234\n> 0005 NOP\n  0006 JMP $0000\n  0009 HLT"
+        );
+
+        cpu.register.pc = 0x0000;
+        assert_eq!(cpu.disassemble_around(2, 1), "> 0000 MVI A,$05\n  0002 LXI BC,$1234");
+        assert_eq!(cpu.disassemble_around(0, 0), "> 0000 MVI A,$05");
+
+        cpu.register.pc = 0xffff;
+        assert_eq!(cpu.disassemble_around(1, 3), "  fffe NOP\n> ffff NOP");
+    }
+
+
+
+    #[test]
+    fn sandbox_blocks_the_restricted_instructions() {
+        //a bus that reads $FF and must never be written
+        struct Ones;
+        impl IoBus for Ones {
+            fn input(&mut self, _port: u8) -> u8 {
+                0xff
+            }
+
+            fn output(&mut self, port: u8, _value: u8) {
+                panic!("OUT {:02x} went through the sandbox", port);
+            }
+        }
+
+        //IN $20; INR B; OUT $10; HLT
+        let mut cpu = cpu_with(&[0xdb, 0x20, 0x04, 0xd3, 0x10, 0x76]);
+        cpu.io = Rc::new(RefCell::new(Ones));
+        cpu.set_sandbox(true);
+        cpu.run_steps(4);
+
+        assert_eq!(cpu.register.a, 0x00);
+        assert_eq!(cpu.register.b, 0x01);
+        assert_eq!(cpu.register.pc, 0x0006);
+        assert!(!cpu.is_halted());
+        assert_eq!(
+            cpu.events(),
+            [
+                Event::Restricted { opcode: 0xdb },
+                Event::Restricted { opcode: 0xd3 },
+                Event::Restricted { opcode: 0x76 },
+            ]
+        );
+
+        cpu.clear_events();
+        cpu.set_sandbox(false);
+        cpu.register.pc = 0x0000;
+        cpu.next();
+        assert_eq!(cpu.register.a, 0xff);
+        assert!(cpu.events().is_empty());
+    }
+
+
+
+    #[test]
+    fn fallthrough_pc_after_lxi() {
+        //LXI H,$1234; JMP $0000
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0200, &[0x21, 0x34, 0x12, 0xc3, 0x00, 0x00]);
+        assert_eq!(cpu.fallthrough_pc(), 0x0203);
+        cpu.next();
+        assert_eq!(cpu.register.pc, 0x0203);
+        //the JMP goes to $0000 but falls through to $0206
+        assert_eq!(cpu.fallthrough_pc(), 0x0206);
+    }
+
+
+
+    #[test]
+    fn step_over_a_call() {
+        //CALL $0010; INR B; ...; MVI A,$42; LXI H,$BEEF; RET at $0010
+        let mut cpu = cpu_with(&[0xcd, 0x10, 0x00, 0x04]);
+        cpu.load_program(0x0010, &[0x3e, 0x42, 0x21, 0xef, 0xbe, 0xc9]);
+        cpu.register.pc = 0x0000;
+        cpu.register.sp = 0x0100;
+
+        assert_eq!(cpu.step_over(10), RunOutcome::Completed);
+        assert_eq!(cpu.register.pc, 0x0003);
+        assert_eq!(cpu.register.sp, 0x0100);
+        assert_eq!(cpu.register.a, 0x42);
+        assert_eq!(cpu.register.get_hl(), 0xbeef);
+        assert_eq!(cpu.register.b, 0x00);
+
+        //a plain instruction is a single step
+        assert_eq!(cpu.step_over(10), RunOutcome::Completed);
+        assert_eq!(cpu.register.pc, 0x0004);
+        assert_eq!(cpu.register.b, 0x01);
+
+        //the subroutine needs 4 steps, CALL included
+        cpu.register.pc = 0x0000;
+        assert_eq!(cpu.step_over(3), RunOutcome::StepLimit);
+        assert_eq!(cpu.register.pc, 0x0015);
+    }
+
+
+
+    //MVI A,$01; CZ $0010; CNZ $0010; HLT; ...; RZ; RNZ at $0010:
+    //the CZ and the RZ are not taken, the CNZ and the RNZ are
+    const CONDITIONAL_CALLS: [u8; 18] = [
+        0x3e, 0x01, 0xcc, 0x10, 0x00, 0xc4, 0x10, 0x00,
+        0x76, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xc8, 0xc0,
+    ];
+    //Cycles of MVI, CZ, CNZ, RZ, RNZ and HLT
+    const CONDITIONAL_CALLS_CYCLES: [u8; 6] = [7, 11, 17, 5, 11, 7];
+
+    #[test]
+    fn last_cycles_of_conditional_calls_and_returns() {
+        let mut cpu = cpu_with(&CONDITIONAL_CALLS);
+        cpu.register.sp = 0x0100;
+        assert_eq!(cpu.last_cycles(), 0);
+        for &cycles in CONDITIONAL_CALLS_CYCLES.iter() {
+            assert_eq!(cpu.next(), cycles);
+            assert_eq!(cpu.last_cycles(), cycles);
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.register.pc, 0x0009);
+        assert_eq!(cpu.register.sp, 0x0100);
+    }
+
+
+
+    #[test]
+    fn run_until_instant_counts_the_cycles() {
+        let mut cpu = cpu_with(&CONDITIONAL_CALLS);
+        cpu.register.sp = 0x0100;
+
+        //a deadline already passed runs nothing
+        assert_eq!(cpu.run_until_instant(Instant::now()), 0);
+        assert_eq!(cpu.register.pc, 0x0000);
+
+        //the program halts long before the deadline
+        let expected: u32 = CONDITIONAL_CALLS_CYCLES.iter().map(|&cycles| u32::from(cycles)).sum();
+        assert_eq!(expected, 58);
+        let deadline = Instant::now() + std::time::Duration::from_secs(60);
+        assert_eq!(cpu.run_until_instant(deadline), expected);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.run_until_instant(deadline), 0);
+    }
+
+
+
+    //A bus that records the port and the value of every IN and OUT,
+    //IN reads the port number plus 1
+    #[derive(Default)]
+    struct RecordingBus {
+        accesses: Vec<(&'static str, u8, u8)>,
+    }
+
+    impl IoBus for RecordingBus {
+        fn input(&mut self, port: u8) -> u8 {
+            let value = port.wrapping_add(1);
+            self.accesses.push(("IN", port, value));
+            value
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.accesses.push(("OUT", port, value));
+        }
+    }
+
+    #[test]
+    fn in_and_out_go_through_the_bus() {
+        //MVI A,$42; OUT $10; IN $20
+        let mut cpu = cpu_with(&[0x3e, 0x42, 0xd3, 0x10, 0xdb, 0x20]);
+        let bus = Rc::new(RefCell::new(RecordingBus::default()));
+        cpu.io = bus.clone();
+        cpu.run_steps(3);
+
+        assert_eq!(cpu.register.a, 0x21);
+        assert_eq!(bus.borrow().accesses, [("OUT", 0x10, 0x42), ("IN", 0x20, 0x21)]);
+    }
+
+
+
+    #[test]
+    fn step_verbose_of_add() {
+        let mut cpu = cpu_with(&KNOWN_ADD);
+        cpu.run_steps(2);
+        assert_eq!(
+            cpu.step_verbose(),
+            VerboseStep {
+                addr: 0x0004,
+                mnemonic: "ADD B",
+                cycles: 4,
+                changes: vec![("A", 0x3a, 0x00), ("F", 0x02, 0x57), ("PC", 0x0004, 0x0005)],
+            }
+        );
+    }
+
+
+
+    #[test]
+    fn opcode_rewriter_turns_hlt_into_nop() {
+        //HLT; INR A; HLT
+        let mut cpu = cpu_with(&[0x76, 0x3c, 0x76]);
+        let rewritten = Rc::new(RefCell::new(Vec::new()));
+        let recorded = rewritten.clone();
+        cpu.set_opcode_rewriter(move |addr, opcode| {
+            if opcode == 0x76 && addr == 0x0000 {
+                recorded.borrow_mut().push(addr);
+                0x00
+            }
+            else {
+                opcode
+            }
+        });
+
+        assert_eq!(cpu.run(), RunOutcome::Halted);
+        assert_eq!(cpu.register.a, 0x01);
+        assert_eq!(cpu.register.pc, 0x0003);
+        assert_eq!(*rewritten.borrow(), [0x0000]);
+        assert_eq!(cpu.memory.borrow().get(0x0000), 0x76);
+    }
+
+
+
+    #[test]
+    fn state_hash_is_fnv1a() {
+        let cpu = cpu_with(&[]);
+        assert_eq!(cpu.state_hash(), cpu_with(&[]).state_hash());
+        assert_eq!(cpu.state_hash(), 0x38a7_226b_3329_f6d5);
+
+        let mut other = cpu_with(&[]);
+        other.register.a = 0x42;
+        assert_ne!(other.state_hash(), cpu.state_hash());
+        assert_eq!(other.state_hash(), 0x8bd1_3fe9_ef56_1b6f);
+
+        other.register.a = 0x00;
+        other.memory.borrow_mut().set(0xffff, 0x01);
+        assert_ne!(other.state_hash(), cpu.state_hash());
+    }
+
+
+
+    #[test]
+    fn sub_and_sbb_auxiliary_carry() {
+        //(opcode, A, B, carry, expected A, expected F): the auxiliary carry is set
+        //when the low nibble doesn't borrow
+        let cases = [
+            (0x90, 0x3e, 0x3e, false, 0x00, 0x56),      //SUB
+            (0x90, 0x10, 0x01, false, 0x0f, 0x06),
+            (0x90, 0x05, 0x02, false, 0x03, 0x16),
+            (0x90, 0x02, 0x05, false, 0xfd, 0x83),
+            (0x98, 0x04, 0x02, true, 0x01, 0x12),       //SBB
+            (0x98, 0x10, 0x00, true, 0x0f, 0x06),
+            (0x98, 0x10, 0x00, false, 0x10, 0x12),
+            (0x98, 0x00, 0x00, true, 0xff, 0x87),
+        ];
+
+        let mut cpu = cpu_with(&[]);
+        for &(opcode, a, b, carry, expected_a, expected_f) in cases.iter() {
+            cpu.register.f = 0x02;
+            cpu.register.set_flag(Flags::Carry, carry);
+            cpu.register.a = a;
+            cpu.register.b = b;
+            cpu.exec_opcode(opcode, &[]);
+            assert_eq!(cpu.register.a, expected_a, "{:02x} {:02x} {:02x}", opcode, a, b);
+            assert_eq!(cpu.register.f, expected_f, "{:02x} {:02x} {:02x}", opcode, a, b);
+            assert_eq!(cpu.register.get_flag(Flags::AC), expected_f & 0x10 != 0);
+        }
+    }
+
+
+
+    #[test]
+    fn reset_keeps_the_memory() {
+        //LXI SP,$0100; MVI A,$42; STA $0080; EI; HLT
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x01, 0x3e, 0x42, 0x32, 0x80, 0x00, 0xfb, 0x76]);
+        cpu.run();
+        assert!(cpu.is_halted());
+        assert!(cpu.total_cycles() > 0);
+
+        cpu.reset();
+        assert_eq!(cpu.register.a, 0x00);
+        assert_eq!(cpu.register.f, 0x02);
+        assert_eq!(cpu.register.sp, 0x0000);
+        assert_eq!(cpu.register.pc, 0x0000);
+        assert!(!cpu.is_halted());
+        assert!(!cpu.interrupts_enabled());
+        assert_eq!(cpu.total_cycles(), 0);
+        assert_eq!(cpu.last_cycles(), 0);
+        assert_eq!(cpu.memory.borrow().get(0x0080), 0x42);
+        assert_eq!(cpu.memory.borrow().get(0x0000), 0x31);
+    }
+
+
+
+    #[test]
+    fn run_frame_delivers_both_interrupts() {
+        //INR B; EI; RET at $0008, INR C; EI; RET at $0010, EI and NOPs from $0040
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0008, &[0x04, 0xfb, 0xc9]);
+        cpu.load_program(0x0010, &[0x0c, 0xfb, 0xc9]);
+        cpu.load_program(0x2400, &[0xaa]);
+        cpu.load_program(0x3fff, &[0x55]);
+        cpu.load_program(0x0040, &[0xfb]);
+        cpu.register.sp = 0x2000;
+
+        //EI and 124 NOPs reach the middle of the frame, RST 1 takes 19 cycles,
+        //121 more NOPs reach the end and RST 2 is requested
+        let frame = cpu.run_frame(1000);
+        assert_eq!(frame.len(), 0x1c00);
+        assert_eq!(frame[0], 0xaa);
+        assert_eq!(frame[0x1bff], 0x55);
+        assert_eq!(cpu.total_cycles(), 1003);
+        assert_eq!(cpu.register.b, 1);
+        assert_eq!(cpu.register.c, 0);
+        assert_eq!(cpu.register.pc, 0x0010);
+        assert_eq!(cpu.peek_return_address(), 0x0136);
+
+        //the RST 2 handler starts the next frame
+        cpu.run_frame(1000);
+        assert_eq!(cpu.total_cycles(), 2005);
+        assert_eq!(cpu.register.b, 2);
+        assert_eq!(cpu.register.c, 1);
+        assert_eq!(cpu.register.sp, 0x1ffe);
+    }
+
+
+
+    #[test]
+    fn countdown_loop_built_with_labels() {
+        //MVI B,$05; loop: INR A; DCR B; JNZ loop; HLT
+        let program = super::super::assembler::Assembler::new(0x0100)
+            .op(&[0x06, 0x05])
+            .label("loop")
+            .op(&[0x3c])
+            .op(&[0x05])
+            .jnz("loop")
+            .op(&[0x76])
+            .build();
+        let mut cpu = cpu_with(&[]);
+        cpu.load_program(0x0100, &program);
+        cpu.enable_branch_profile();
+
+        assert_eq!(cpu.run(), RunOutcome::Halted);
+        assert_eq!(cpu.register.a, 5);
+        assert_eq!(cpu.register.b, 0);
+        assert_eq!(cpu.branch_profile()[&0x0104], (4, 1));
+    }
+
+
+
+    #[test]
+    fn run_stops_on_hlt_and_run_steps_on_the_count() {
+        //INR A; INR A; HLT; INR A
+        let mut cpu = cpu_with(&[0x3c, 0x3c, 0x76, 0x3c]);
+        assert_eq!(cpu.run(), RunOutcome::Halted);
+        assert_eq!(cpu.register.a, 2);
+        assert_eq!(cpu.register.pc, 0x0003);
+        assert_eq!(cpu.run_steps(10), 0);
+
+        //JMP $0000 never ends by itself
+        let mut cpu = cpu_with(&[0xc3, 0x00, 0x00]);
+        assert_eq!(cpu.run_steps(1000), 1000);
+        assert_eq!(cpu.register.pc, 0x0000);
+        assert_eq!(cpu.total_cycles(), 10_000);
+        assert!(cpu.is_idle());
+    }
+
+
+
+    #[test]
+    fn sp_hook_sees_push_and_pop() {
+        //LXI SP,$0100; PUSH B; POP D; INX SP
+        let mut cpu = cpu_with(&[0x31, 0x00, 0x01, 0xc5, 0xd1, 0x33]);
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let recorded = changes.clone();
+        cpu.set_sp_hook(move |old, new| recorded.borrow_mut().push((old, new)));
+        cpu.run_steps(4);
+
+        assert_eq!(
+            *changes.borrow(),
+            [(0x0000, 0x0100), (0x0100, 0x00fe), (0x00fe, 0x0100), (0x0100, 0x0101)]
+        );
+    }
+
+
+
+    #[test]
+    fn stax_and_ldax() {
+        //LXI B,$2000; LXI D,$3000; MVI A,$42; STAX B; MVI A,$99; STAX D;
+        //MVI A,$00; LDAX B; LDAX D
+        let mut cpu = cpu_with(&[
+            0x01, 0x00, 0x20, 0x11, 0x00, 0x30, 0x3e, 0x42, 0x02, 0x3e, 0x99, 0x12,
+            0x3e, 0x00, 0x0a, 0x1a,
+        ]);
+        cpu.register.f = 0xd7;
+        cpu.run_steps(6);
+        assert_eq!(cpu.memory.borrow().get(0x2000), 0x42);
+        assert_eq!(cpu.memory.borrow().get(0x3000), 0x99);
+
+        cpu.run_steps(2);
+        assert_eq!(cpu.register.a, 0x42);
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x99);
+        assert_eq!(cpu.register.f, 0xd7);
+        assert_eq!(cpu.register.get_bc(), 0x2000);
+        assert_eq!(cpu.register.get_de(), 0x3000);
+    }
+
+
+
+    #[test]
+    fn total_cycles_of_conditional_calls_and_returns() {
+        let mut cpu = cpu_with(&CONDITIONAL_CALLS);
+        cpu.register.sp = 0x0100;
+        let mut expected = 0;
+        for &cycles in CONDITIONAL_CALLS_CYCLES.iter() {
+            cpu.next();
+            expected += u64::from(cycles);
+            assert_eq!(cpu.total_cycles(), expected);
+        }
+        assert_eq!(expected, 58);
+
+        //a halted cpu keeps counting 4 cycles per step
+        cpu.run_steps(2);
+        assert_eq!(cpu.total_cycles(), 58);
+        cpu.next();
+        assert_eq!(cpu.total_cycles(), 62);
+    }
+
+
+
+    #[test]
+    fn would_parity_be_even() {
+        assert!(Cpu::would_parity_be_even(0x03));
+        assert!(!Cpu::would_parity_be_even(0x07));
+        assert!(Cpu::would_parity_be_even(0x00));
+        assert!(Cpu::would_parity_be_even(0xff));
+        assert!(!Cpu::would_parity_be_even(0x80));
+
+        //ORI $00 sets Parity like the query says
+        let mut cpu = cpu_with(&[]);
+        for &value in [0x03, 0x07].iter() {
+            cpu.register.a = value;
+            cpu.exec_opcode(0xf6, &[0x00]);
+            assert_eq!(cpu.flag(Flags::Parity), Cpu::would_parity_be_even(value));
+        }
+    }
+
+
+
+    #[test]
+    fn forced_carry_is_added_by_adc() {
+        //ADC B
+        let mut cpu = cpu_with(&[0x88, 0x88]);
+        cpu.register_mut().a = 0x10;
+        cpu.register_mut().b = 0x20;
+        cpu.set_flag(Flags::Carry, true);
+        assert!(cpu.flag(Flags::Carry));
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x31);
+        assert!(!cpu.flag(Flags::Carry));
+
+        //the same through register_mut
+        cpu.register_mut().set_flag(Flags::Carry, true);
+        cpu.next();
+        assert_eq!(cpu.register.a, 0x52);
+    }
+
+
+
+    #[test]
+    fn self_test_passes() {
+        assert_eq!(Cpu::self_test(), Ok(()));
+    }
+
+    #[test]
+    fn self_test_reports_a_wrong_row() {
+        //ADD $3A + $C6 is $00 with F $57, not $01
+        let cases = [
+            ("SUB", 0x3e, 0x3e, false, 0x90, 0x00, 0x56),
+            ("ADD", 0x3a, 0xc6, false, 0x80, 0x01, 0x57),
+            ("DCR", 0x00, 0x00, false, 0x3d, 0x00, 0x00),
+        ];
+        assert_eq!(
+            Cpu::self_test_with(&cases),
+            Err(SelfTestError { operation: "ADD", expected: (0x01, 0x57), found: (0x00, 0x57) })
+        );
+    }
+
+
+
+    #[test]
+    fn debug_format() {
+        let mut cpu = cpu_with(&KNOWN_ADD);
+        cpu.run_steps(3);
+        let text = format!("{:?}", cpu);
+        assert_eq!(
+            text,
+            "Cpu { register: Register { a: 0, f: 87, b: 198, c: 0, d: 0, e: 0, h: 0, l: 0, \
+             sp: 0, pc: 5 }, stop: false, interrupt: false, memory_len: 65536 }"
+        );
+        assert!(text.contains("pc:"));
+        assert!(text.len() < 200);
+    }
+}
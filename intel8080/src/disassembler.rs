@@ -1,3 +1,6 @@
+use std::collections::BTreeSet;
+use std::ops::Range;
+
 #[allow(dead_code)]
 pub fn get_mnemonic(opcode: u8) -> &'static str {
     match opcode {
@@ -258,4 +261,354 @@ pub fn get_mnemonic(opcode: u8) -> &'static str {
         0xFE => "CPI       ",
         0xFF => "RST 7     ",
     }
-}
\ No newline at end of file
+}
+
+//Returns the number of bytes (opcode + operands) used by the instruction
+pub fn get_length(opcode: u8) -> usize {
+    match opcode {
+        0x06 | 0x0e | 0x16 | 0x1e | 0x26 | 0x2e | 0x36 | 0x3e |                    //MVI
+        0xc6 | 0xce | 0xd6 | 0xde | 0xe6 | 0xee | 0xf6 | 0xfe |                     //Immediate ALU operations
+        0xd3 | 0xdb => 2,                                                           //OUT, IN
+        0x01 | 0x11 | 0x21 | 0x31 |                                                 //LXI
+        0x22 | 0x2a | 0x32 | 0x3a |                                                 //SHLD, LHLD, STA, LDA
+        0xc2 | 0xc3 | 0xca | 0xcb | 0xd2 | 0xda | 0xe2 | 0xea | 0xf2 | 0xfa |       //JMP and Jump condition
+        0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc | 0xdd | 0xe4 | 0xec | 0xed | 0xf4 | 0xfc | 0xfd => 3,   //CALL and Call condition
+        _ => 1,
+    }
+}
+
+//Number of cycles used by each instruction, the conditional CALL and RET
+//use 6 more cycles when the condition is true
+const CYCLES: [u8; 256] = [
+//  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4,     //0
+    4, 10,  7,  5,  5,  5,  7,  4,  4, 10,  7,  5,  5,  5,  7,  4,     //1
+    4, 10, 16,  5,  5,  5,  7,  4,  4, 10, 16,  5,  5,  5,  7,  4,     //2
+    4, 10, 13,  5, 10, 10, 10,  4,  4, 10, 13,  5,  5,  5,  7,  4,     //3
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,     //4
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,     //5
+    5,  5,  5,  5,  5,  5,  7,  5,  5,  5,  5,  5,  5,  5,  7,  5,     //6
+    7,  7,  7,  7,  7,  7,  7,  7,  5,  5,  5,  5,  5,  5,  7,  5,     //7
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,     //8
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,     //9
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,     //A
+    4,  4,  4,  4,  4,  4,  7,  4,  4,  4,  4,  4,  4,  4,  7,  4,     //B
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11,     //C
+    5, 10, 10, 10, 11, 11,  7, 11,  5, 10, 10, 10, 11, 17,  7, 11,     //D
+    5, 10, 10, 18, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11,     //E
+    5, 10, 10,  4, 11, 11,  7, 11,  5,  5, 10,  4, 11, 17,  7, 11,     //F
+];
+
+//Returns the number of cycles used by the instruction, for the conditional
+//CALL and RET it's the number of cycles used when the condition is false
+pub fn get_cycles(opcode: u8) -> u8 {
+    CYCLES[usize::from(opcode)]
+}
+
+// This struct contains everything known about an opcode without executing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpcodeInfo {
+    pub mnemonic: &'static str,
+    pub length: usize,
+    pub cycles: u8,
+}
+
+pub fn opcode_info(opcode: u8) -> OpcodeInfo {
+    OpcodeInfo {
+        mnemonic: get_mnemonic(opcode).trim_end(),
+        length: get_length(opcode),
+        cycles: get_cycles(opcode),
+    }
+}
+
+//Returns every opcode, from 0x00 to 0xff, with its OpcodeInfo
+pub fn opcode_table() -> impl Iterator<Item = (u8, OpcodeInfo)> {
+    (0x00..=0xff).map(|opcode| (opcode, opcode_info(opcode)))
+}
+
+//Returns true for the instructions that control the machine or talk with the
+//devices: HLT, EI, DI, IN and OUT
+pub fn opcode_is_restricted(opcode: u8) -> bool {
+    matches!(opcode, 0x76 | 0xfb | 0xf3 | 0xdb | 0xd3)
+}
+
+// This struct represents a single instruction found inside a ROM
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedInstruction {
+    pub addr: usize,
+    pub opcode: u8,
+    pub length: usize,
+    pub mnemonic: &'static str,
+}
+
+//Decodes the instruction starting at addr, panics if addr is past the end of rom
+pub fn disassemble_one(rom: &[u8], addr: usize) -> DecodedInstruction {
+    let opcode = rom[addr];
+    DecodedInstruction {
+        addr,
+        opcode,
+        length: get_length(opcode),
+        mnemonic: get_mnemonic(opcode).trim_end(),
+    }
+}
+
+//Returns the text of the instruction starting at offset, operands included
+//(e.g. "LXI BC,$34FF", "MVI A,$05", "JMP $1234"), and its length.
+//The operands past the end of bytes are read as 0, the opcode has to be inside:
+//it panics if offset is past the end of bytes
+pub fn decode(bytes: &[u8], offset: usize) -> (String, usize) {
+    let instruction = disassemble_one(bytes, offset);
+    let operand = |idx: usize| bytes.get(offset + idx).copied().unwrap_or(0);
+    let separator = if instruction.mnemonic.contains(' ') { ',' } else { ' ' };
+
+    let text = match instruction.length {
+        2 => format!("{}{}${:02X}", instruction.mnemonic, separator, operand(1)),
+        3 => format!("{}{}${:02X}{:02X}", instruction.mnemonic, separator, operand(2), operand(1)),
+        _ => instruction.mnemonic.to_string(),
+    };
+    (text, instruction.length)
+}
+
+//Disassembles the whole rom, one line per instruction with its address (e.g. "0003 MVI A,$05")
+pub fn disassemble_lines(rom: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let (text, length) = decode(rom, pos);
+        lines.push(format!("{:04x} {}", pos, text));
+        pos += length;
+    }
+    lines
+}
+
+//Returns (offset, length) of every instruction of rom in the same order as disassemble_lines,
+//the last instruction is cut at the end of rom so the spans cover every byte exactly once
+pub fn line_spans(rom: &[u8]) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let length = get_length(rom[pos]).min(rom.len() - pos);
+        spans.push((pos, length));
+        pos += length;
+    }
+    spans
+}
+
+//Returns the count instructions that precede addr
+//Instructions have different lengths so there is no way to know where the previous one
+//starts: a linear sweep is started from every address in the 3 * count bytes before addr
+//and the first sweep that lands exactly on addr is used.
+//This is only a heuristic: data mixed with code or a sweep that synchronizes by chance
+//can produce instructions that are never executed, and if no sweep lands on addr
+//an empty Vec is returned
+pub fn disassemble_backward(rom: &[u8], addr: usize, count: usize) -> Vec<DecodedInstruction> {
+    let addr = addr.min(rom.len());
+    for start in addr.saturating_sub(count * 3)..addr {
+        let mut instructions = Vec::new();
+        let mut pos = start;
+        while pos < addr {
+            let instruction = disassemble_one(rom, pos);
+            pos += instruction.length;
+            instructions.push(instruction);
+        }
+
+        if pos == addr {
+            let skip = instructions.len().saturating_sub(count);
+            return instructions.split_off(skip);
+        }
+    }
+
+    Vec::new()
+}
+
+//Returns the bytes used by the subroutine starting at entry
+//The instructions are walked linearly until the first unconditional RET, so jumps
+//are not followed and a subroutine with more than one exit stops at the first one.
+//If no RET is found the range ends with the rom
+pub fn subroutine_extent(rom: &[u8], entry: usize) -> Range<usize> {
+    let mut pos = entry;
+    while pos < rom.len() {
+        let instruction = disassemble_one(rom, pos);
+        pos += instruction.length;
+        if instruction.opcode == 0xc9 || instruction.opcode == 0xd9 {
+            return entry..pos;
+        }
+    }
+
+    entry..rom.len()
+}
+
+//Returns the offset and the text of every run of at least min_len printable ASCII characters.
+//CP/M strings end with '$', so a '$' closes the string and it's not part of the text
+pub fn find_strings(rom: &[u8], min_len: usize) -> Vec<(usize, String)> {
+    let mut strings = Vec::new();
+    let mut start = 0;
+    let mut text = String::new();
+
+    for (idx, &byte) in rom.iter().enumerate() {
+        if byte != b'$' && (0x20..=0x7e).contains(&byte) {
+            if text.is_empty() {
+                start = idx;
+            }
+            text.push(char::from(byte));
+        }
+        else {
+            if text.len() >= min_len && !text.is_empty() {
+                strings.push((start, text.clone()));
+            }
+            text.clear();
+        }
+    }
+
+    if text.len() >= min_len && !text.is_empty() {
+        strings.push((start, text));
+    }
+
+    strings
+}
+
+//Returns the addresses read or written by the direct memory instructions
+//(SHLD, LHLD, STA, LDA) found walking the rom linearly
+pub fn referenced_addresses(rom: &[u8]) -> BTreeSet<u16> {
+    let mut addresses = BTreeSet::new();
+    let mut pos = 0;
+    while pos < rom.len() {
+        let instruction = disassemble_one(rom, pos);
+        if matches!(instruction.opcode, 0x22 | 0x2a | 0x32 | 0x3a) && pos + 2 < rom.len() {
+            addresses.insert(u16::from(rom[pos + 1]) | (u16::from(rom[pos + 2]) << 8));
+        }
+        pos += instruction.length;
+    }
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //The first bytes of the Space Invaders ROM (invaders.h)
+    const INVADERS_HEADER: [u8; 24] = [
+        0x00, 0x00, 0x00, 0xc3, 0xd4, 0x18, 0x00, 0x00,
+        0xf5, 0xc5, 0xd5, 0xe5, 0xc3, 0x8c, 0x00, 0x00,
+        0xf5, 0xc5, 0xd5, 0xe5, 0x3e, 0x80, 0x32, 0x72,
+    ];
+
+    #[test]
+    fn decode_the_space_invaders_header() {
+        let expected = [
+            "NOP", "NOP", "NOP", "JMP $18D4", "NOP", "NOP",
+            "PUSH PSW", "PUSH B", "PUSH D", "PUSH H", "JMP $008C", "NOP",
+            "PUSH PSW", "PUSH B", "PUSH D", "PUSH H", "MVI A,$80",
+            //STA $2072 is cut after its low byte, the missing one is read as 0
+            "STA $0072",
+        ];
+
+        let mut offset = 0;
+        for &mnemonic in expected.iter() {
+            let (text, length) = decode(&INVADERS_HEADER, offset);
+            assert_eq!(text, mnemonic, "at {:04x}", offset);
+            offset += length;
+        }
+        assert_eq!(offset, INVADERS_HEADER.len() + 1);
+    }
+
+
+    #[test]
+    fn disassemble_lines_of_a_buffer() {
+        //LXI B,$34FF; NOP; MVI A,$05
+        let lines = disassemble_lines(&[0x01, 0xff, 0x34, 0x00, 0x3e, 0x05]);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "0000 LXI BC,$34FF");
+        assert_eq!(lines[2], "0004 MVI A,$05");
+    }
+
+
+    #[test]
+    fn disassemble_backward_finds_the_previous_instructions() {
+        //LXI B,$1234; MVI A,$05; NOP; INR A
+        let rom = [0x01, 0x34, 0x12, 0x3e, 0x05, 0x00, 0x3c];
+        let addrs = |instructions: Vec<DecodedInstruction>| -> Vec<usize> {
+            instructions.iter().map(|instruction| instruction.addr).collect()
+        };
+
+        assert_eq!(addrs(disassemble_backward(&rom, 7, 2)), [5, 6]);
+        assert_eq!(addrs(disassemble_backward(&rom, 7, 3)), [3, 5, 6]);
+        let previous = disassemble_backward(&rom, 5, 1);
+        assert_eq!(previous[0].mnemonic, "MVI A");
+        assert!(disassemble_backward(&rom, 0, 4).is_empty());
+    }
+
+
+    #[test]
+    fn subroutine_extent_ends_with_ret() {
+        //NOP; MVI A,$05; RZ; INR A; RET; NOP
+        let rom = [0x00, 0x00, 0x3e, 0x05, 0xc8, 0x3c, 0xc9, 0x00];
+        assert_eq!(subroutine_extent(&rom, 1), 1..7);
+        assert_eq!(subroutine_extent(&rom, 7), 7..8);
+    }
+
+
+
+    #[test]
+    fn opcode_table_covers_every_opcode() {
+        let table: Vec<(u8, OpcodeInfo)> = opcode_table().collect();
+        assert_eq!(table.len(), 256);
+        for (idx, (opcode, info)) in table.iter().enumerate() {
+            assert_eq!(usize::from(*opcode), idx);
+            assert!(!info.mnemonic.is_empty(), "opcode {:02x}", opcode);
+            assert!((1..=3).contains(&info.length), "opcode {:02x}", opcode);
+            assert!(info.cycles > 0, "opcode {:02x}", opcode);
+        }
+        assert_eq!(opcode_info(0xcd), OpcodeInfo { mnemonic: "CALL", length: 3, cycles: 17 });
+    }
+
+
+    #[test]
+    fn restricted_opcodes() {
+        let restricted: Vec<u8> = (0x00..=0xff).filter(|&opcode| opcode_is_restricted(opcode)).collect();
+        assert_eq!(restricted, [0x76, 0xd3, 0xdb, 0xf3, 0xfb]);
+    }
+
+
+
+    #[test]
+    fn find_strings_in_a_rom() {
+        let rom = b"\x00HELLO$\x01HI\x00WORLD";
+        assert_eq!(find_strings(rom, 3), [(1, "HELLO".to_string()), (11, "WORLD".to_string())]);
+        assert_eq!(find_strings(rom, 2)[1], (8, "HI".to_string()));
+        assert_eq!(find_strings(rom, 0).len(), 3);
+        assert_eq!(find_strings(b"$$OK$", 1), [(2, "OK".to_string())]);
+    }
+
+
+
+    #[test]
+    fn referenced_addresses_of_sta_and_lhld() {
+        //STA $2072; LHLD $1234; MVI A,$32; STA $2072; JMP $4000; LDA cut after its low byte
+        let rom = [
+            0x32, 0x72, 0x20, 0x2a, 0x34, 0x12, 0x3e, 0x32, 0x32, 0x72, 0x20,
+            0xc3, 0x00, 0x40, 0x3a, 0x00,
+        ];
+        let addresses: Vec<u16> = referenced_addresses(&rom).into_iter().collect();
+        assert_eq!(addresses, [0x1234, 0x2072]);
+    }
+
+
+
+    #[test]
+    fn line_spans_tile_the_buffer() {
+        //NOP; MVI A,$05; LXI H,$1234; INR A; JMP cut after its low byte
+        let rom = [0x00, 0x3e, 0x05, 0x21, 0x34, 0x12, 0x3c, 0xc3, 0x00];
+        let spans = line_spans(&rom);
+        assert_eq!(spans, [(0, 1), (1, 2), (3, 3), (6, 1), (7, 2)]);
+        assert_eq!(spans.len(), disassemble_lines(&rom).len());
+
+        let mut next = 0;
+        for &(offset, length) in spans.iter() {
+            assert_eq!(offset, next);
+            next += length;
+        }
+        assert_eq!(next, rom.len());
+        assert!(line_spans(&[]).is_empty());
+    }
+}
@@ -0,0 +1,30 @@
+// This table contains the Parity flag for every result:
+// true if the number of 1 bits is even
+// it replaces count_ones in the hot path of the ALU
+pub const PARITY: [bool; 256] = parity_table();
+
+const fn parity_table() -> [bool; 256] {
+    let mut table = [false; 256];
+    let mut value = 0;
+    while value < 256 {
+        table[value] = (value as u8).count_ones() & 0x01 == 0x00;
+        value += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parity_table_matches_count_ones() {
+        for value in 0..=255u8 {
+            assert_eq!(PARITY[usize::from(value)], value.count_ones() & 1 == 0, "{:02x}", value);
+        }
+        assert!(PARITY[0x00]);
+        assert!(PARITY[0x03]);
+        assert!(!PARITY[0x07]);
+        assert!(PARITY[0xff]);
+    }
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+
+// This enum represents the 8 bits registers as they are encoded in the opcodes:
+// the value of each variant is its 3 bits code, M is the memory pointed by HL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    B = 0,
+    C = 1,
+    D = 2,
+    E = 3,
+    H = 4,
+    L = 5,
+    M = 6,
+    A = 7,
+}
+
+// This enum represents the register pairs used by LXI, INX, DCX, DAD, LDAX and STAX
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegPair {
+    B,
+    D,
+    H,
+    SP,
+}
+
+// This enum represents the register pairs used by PUSH and POP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackPair {
+    B,
+    D,
+    H,
+    PSW,
+}
+
+// This enum represents the conditions of the conditional jumps, calls and returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    NZ,     //Not Zero
+    Z,      //Zero
+    NC,     //No Carry
+    C,      //Carry
+    PO,     //Parity Odd
+    PE,     //Parity Even
+    P,      //Positive
+    M,      //Negative
+}
+
+// This enum represents a decoded instruction with its operands,
+// the undocumented opcodes are decoded as the instruction they run
+// (e.g. 0x08 is a Nop and 0xcb is a Jmp)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    //Data Transfer Group
+    Mov { dst: Reg, src: Reg },
+    Mvi(Reg, u8),
+    Lxi(RegPair, u16),
+    Lda(u16),
+    Sta(u16),
+    Lhld(u16),
+    Shld(u16),
+    Ldax(RegPair),
+    Stax(RegPair),
+    Xchg,
+    //Arithmetic Group
+    Add(Reg),
+    Adi(u8),
+    Adc(Reg),
+    Aci(u8),
+    Sub(Reg),
+    Sui(u8),
+    Sbb(Reg),
+    Sbi(u8),
+    Inr(Reg),
+    Dcr(Reg),
+    Inx(RegPair),
+    Dcx(RegPair),
+    Dad(RegPair),
+    Daa,
+    //Logical Group
+    Ana(Reg),
+    Ani(u8),
+    Xra(Reg),
+    Xri(u8),
+    Ora(Reg),
+    Ori(u8),
+    Cmp(Reg),
+    Cpi(u8),
+    Rlc,
+    Rrc,
+    Ral,
+    Rar,
+    Cma,
+    Cmc,
+    Stc,
+    //Branch Group
+    Jmp(u16),
+    Jcond(Condition, u16),
+    Call(u16),
+    Ccond(Condition, u16),
+    Ret,
+    Rcond(Condition),
+    Rst(u8),
+    Pchl,
+    //Stack, I/O and Machine Control Group
+    Push(StackPair),
+    Pop(StackPair),
+    Xthl,
+    Sphl,
+    In(u8),
+    Out(u8),
+    Ei,
+    Di,
+    Hlt,
+    Nop,
+}
+
+impl Reg {
+    //Returns the register encoded by the 3 lower bits of code
+    pub fn from_code(code: u8) -> Reg {
+        match code & 0x07 {
+            0 => Reg::B,
+            1 => Reg::C,
+            2 => Reg::D,
+            3 => Reg::E,
+            4 => Reg::H,
+            5 => Reg::L,
+            6 => Reg::M,
+            _ => Reg::A,
+        }
+    }
+}
+
+impl RegPair {
+    fn from_code(code: u8) -> RegPair {
+        match code & 0x03 {
+            0 => RegPair::B,
+            1 => RegPair::D,
+            2 => RegPair::H,
+            _ => RegPair::SP,
+        }
+    }
+}
+
+impl StackPair {
+    fn from_code(code: u8) -> StackPair {
+        match code & 0x03 {
+            0 => StackPair::B,
+            1 => StackPair::D,
+            2 => StackPair::H,
+            _ => StackPair::PSW,
+        }
+    }
+}
+
+impl Condition {
+    fn from_code(code: u8) -> Condition {
+        match code & 0x07 {
+            0 => Condition::NZ,
+            1 => Condition::Z,
+            2 => Condition::NC,
+            3 => Condition::C,
+            4 => Condition::PO,
+            5 => Condition::PE,
+            6 => Condition::P,
+            _ => Condition::M,
+        }
+    }
+}
+
+//Decodes opcode, next is called to read each byte of the operands in memory order
+//The fields of the opcode are used to find the operands:
+//  bits 5-3: destination register, ALU operation, condition or RST number
+//  bits 5-4: register pair
+//  bits 2-0: source register
+pub fn decode_instruction(opcode: u8, mut next: impl FnMut() -> u8) -> Instruction {
+    let mut word = || {
+        let low = next();
+        let high = next();
+        (u16::from(high) << 8) | u16::from(low)
+    };
+    let dst = Reg::from_code(opcode >> 3);
+    let src = Reg::from_code(opcode);
+    let pair = RegPair::from_code(opcode >> 4);
+    let condition = Condition::from_code(opcode >> 3);
+
+    match opcode {
+        0x76 => Instruction::Hlt,
+        0x40..=0x7f => Instruction::Mov { dst, src },
+        0x80..=0xbf => match (opcode >> 3) & 0x07 {
+            0 => Instruction::Add(src),
+            1 => Instruction::Adc(src),
+            2 => Instruction::Sub(src),
+            3 => Instruction::Sbb(src),
+            4 => Instruction::Ana(src),
+            5 => Instruction::Xra(src),
+            6 => Instruction::Ora(src),
+            _ => Instruction::Cmp(src),
+        },
+        0x00..=0x3f => match opcode & 0x07 {
+            1 if opcode & 0x08 == 0 => Instruction::Lxi(pair, word()),
+            1 => Instruction::Dad(pair),
+            2 => match opcode {
+                0x02 | 0x12 => Instruction::Stax(pair),
+                0x0a | 0x1a => Instruction::Ldax(pair),
+                0x22 => Instruction::Shld(word()),
+                0x2a => Instruction::Lhld(word()),
+                0x32 => Instruction::Sta(word()),
+                _ => Instruction::Lda(word()),
+            },
+            3 if opcode & 0x08 == 0 => Instruction::Inx(pair),
+            3 => Instruction::Dcx(pair),
+            4 => Instruction::Inr(dst),
+            5 => Instruction::Dcr(dst),
+            6 => Instruction::Mvi(dst, next()),
+            7 => match (opcode >> 3) & 0x07 {
+                0 => Instruction::Rlc,
+                1 => Instruction::Rrc,
+                2 => Instruction::Ral,
+                3 => Instruction::Rar,
+                4 => Instruction::Daa,
+                5 => Instruction::Cma,
+                6 => Instruction::Stc,
+                _ => Instruction::Cmc,
+            },
+            _ => Instruction::Nop,
+        },
+        _ => match opcode & 0x07 {
+            0 => Instruction::Rcond(condition),
+            1 => match opcode {
+                0xc9 | 0xd9 => Instruction::Ret,
+                0xe9 => Instruction::Pchl,
+                0xf9 => Instruction::Sphl,
+                _ => Instruction::Pop(StackPair::from_code(opcode >> 4)),
+            },
+            2 => Instruction::Jcond(condition, word()),
+            3 => match opcode {
+                0xd3 => Instruction::Out(next()),
+                0xdb => Instruction::In(next()),
+                0xe3 => Instruction::Xthl,
+                0xeb => Instruction::Xchg,
+                0xf3 => Instruction::Di,
+                0xfb => Instruction::Ei,
+                _ => Instruction::Jmp(word()),
+            },
+            4 => Instruction::Ccond(condition, word()),
+            5 if opcode & 0x08 == 0 => Instruction::Push(StackPair::from_code(opcode >> 4)),
+            5 => Instruction::Call(word()),
+            6 => match (opcode >> 3) & 0x07 {
+                0 => Instruction::Adi(next()),
+                1 => Instruction::Aci(next()),
+                2 => Instruction::Sui(next()),
+                3 => Instruction::Sbi(next()),
+                4 => Instruction::Ani(next()),
+                5 => Instruction::Xri(next()),
+                6 => Instruction::Ori(next()),
+                _ => Instruction::Cpi(next()),
+            },
+            _ => Instruction::Rst((opcode >> 3) & 0x07),
+        },
+    }
+}
+
+// This impl prints the registers with the names used by the 8080 assembly
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for RegPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for StackPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// This impl prints the instructions in 8080 assembly, e.g. "MOV A,M", "LXI SP,$2400", "JNZ $0100"
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Mov { dst, src } => write!(f, "MOV {},{}", dst, src),
+            Instruction::Mvi(reg, data) => write!(f, "MVI {},${:02X}", reg, data),
+            Instruction::Lxi(pair, data) => write!(f, "LXI {},${:04X}", pair, data),
+            Instruction::Lda(addr) => write!(f, "LDA ${:04X}", addr),
+            Instruction::Sta(addr) => write!(f, "STA ${:04X}", addr),
+            Instruction::Lhld(addr) => write!(f, "LHLD ${:04X}", addr),
+            Instruction::Shld(addr) => write!(f, "SHLD ${:04X}", addr),
+            Instruction::Ldax(pair) => write!(f, "LDAX {}", pair),
+            Instruction::Stax(pair) => write!(f, "STAX {}", pair),
+            Instruction::Xchg => write!(f, "XCHG"),
+            Instruction::Add(reg) => write!(f, "ADD {}", reg),
+            Instruction::Adi(data) => write!(f, "ADI ${:02X}", data),
+            Instruction::Adc(reg) => write!(f, "ADC {}", reg),
+            Instruction::Aci(data) => write!(f, "ACI ${:02X}", data),
+            Instruction::Sub(reg) => write!(f, "SUB {}", reg),
+            Instruction::Sui(data) => write!(f, "SUI ${:02X}", data),
+            Instruction::Sbb(reg) => write!(f, "SBB {}", reg),
+            Instruction::Sbi(data) => write!(f, "SBI ${:02X}", data),
+            Instruction::Inr(reg) => write!(f, "INR {}", reg),
+            Instruction::Dcr(reg) => write!(f, "DCR {}", reg),
+            Instruction::Inx(pair) => write!(f, "INX {}", pair),
+            Instruction::Dcx(pair) => write!(f, "DCX {}", pair),
+            Instruction::Dad(pair) => write!(f, "DAD {}", pair),
+            Instruction::Daa => write!(f, "DAA"),
+            Instruction::Ana(reg) => write!(f, "ANA {}", reg),
+            Instruction::Ani(data) => write!(f, "ANI ${:02X}", data),
+            Instruction::Xra(reg) => write!(f, "XRA {}", reg),
+            Instruction::Xri(data) => write!(f, "XRI ${:02X}", data),
+            Instruction::Ora(reg) => write!(f, "ORA {}", reg),
+            Instruction::Ori(data) => write!(f, "ORI ${:02X}", data),
+            Instruction::Cmp(reg) => write!(f, "CMP {}", reg),
+            Instruction::Cpi(data) => write!(f, "CPI ${:02X}", data),
+            Instruction::Rlc => write!(f, "RLC"),
+            Instruction::Rrc => write!(f, "RRC"),
+            Instruction::Ral => write!(f, "RAL"),
+            Instruction::Rar => write!(f, "RAR"),
+            Instruction::Cma => write!(f, "CMA"),
+            Instruction::Cmc => write!(f, "CMC"),
+            Instruction::Stc => write!(f, "STC"),
+            Instruction::Jmp(addr) => write!(f, "JMP ${:04X}", addr),
+            Instruction::Jcond(condition, addr) => write!(f, "J{} ${:04X}", condition, addr),
+            Instruction::Call(addr) => write!(f, "CALL ${:04X}", addr),
+            Instruction::Ccond(condition, addr) => write!(f, "C{} ${:04X}", condition, addr),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Rcond(condition) => write!(f, "R{}", condition),
+            Instruction::Rst(n) => write!(f, "RST {}", n),
+            Instruction::Pchl => write!(f, "PCHL"),
+            Instruction::Push(pair) => write!(f, "PUSH {}", pair),
+            Instruction::Pop(pair) => write!(f, "POP {}", pair),
+            Instruction::Xthl => write!(f, "XTHL"),
+            Instruction::Sphl => write!(f, "SPHL"),
+            Instruction::In(port) => write!(f, "IN ${:02X}", port),
+            Instruction::Out(port) => write!(f, "OUT ${:02X}", port),
+            Instruction::Ei => write!(f, "EI"),
+            Instruction::Di => write!(f, "DI"),
+            Instruction::Hlt => write!(f, "HLT"),
+            Instruction::Nop => write!(f, "NOP"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Decodes the instruction at the start of bytes
+    fn decode(bytes: &[u8]) -> Instruction {
+        let mut operands = bytes[1..].iter().copied();
+        decode_instruction(bytes[0], || operands.next().unwrap())
+    }
+
+    #[test]
+    fn decode_a_few_opcodes() {
+        assert_eq!(decode(&[0x00]), Instruction::Nop);
+        assert_eq!(decode(&[0x08]), Instruction::Nop);
+        assert_eq!(decode(&[0x01, 0xff, 0x34]), Instruction::Lxi(RegPair::B, 0x34ff));
+        assert_eq!(decode(&[0x31, 0x00, 0x24]), Instruction::Lxi(RegPair::SP, 0x2400));
+        assert_eq!(decode(&[0x3e, 0x05]), Instruction::Mvi(Reg::A, 0x05));
+        assert_eq!(decode(&[0x78]), Instruction::Mov { dst: Reg::A, src: Reg::B });
+        assert_eq!(decode(&[0x76]), Instruction::Hlt);
+        assert_eq!(decode(&[0x86]), Instruction::Add(Reg::M));
+        assert_eq!(decode(&[0xfe, 0x10]), Instruction::Cpi(0x10));
+        assert_eq!(decode(&[0xca, 0x34, 0x12]), Instruction::Jcond(Condition::Z, 0x1234));
+        assert_eq!(decode(&[0xcb, 0x34, 0x12]), Instruction::Jmp(0x1234));
+        assert_eq!(decode(&[0xf5]), Instruction::Push(StackPair::PSW));
+        assert_eq!(decode(&[0xd7]), Instruction::Rst(2));
+        assert_eq!(decode(&[0xd3, 0x02]), Instruction::Out(0x02));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(decode(&[0x3e, 0x05]).to_string(), "MVI A,$05");
+        assert_eq!(decode(&[0x78]).to_string(), "MOV A,B");
+        assert_eq!(decode(&[0xca, 0x34, 0x12]).to_string(), "JZ $1234");
+    }
+}
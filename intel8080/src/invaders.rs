@@ -0,0 +1,149 @@
+use std::ops::Range;
+
+use super::io::IoBus;
+
+// Space Invaders runs the i8080 at 2MHz and asks for two interrupts each frame (60 per second):
+// RST 1 halfway through the frame, when the beam is in the middle of the screen
+// RST 2 at the end of the frame, when vblank starts (scanline 224)
+// See: http://computerarcheology.com/Arcade/SpaceInvaders/Hardware.html
+pub const CYCLES_PER_FRAME: u32 = 2_000_000 / 60;
+
+//The screen is 256x224 pixels, 1 bit per pixel, stored in this range of the memory
+pub const VIDEO_RAM: Range<usize> = 0x2400..0x4000;
+
+pub struct SpaceInvadersTiming {
+    pub cycles_per_frame: u32,
+}
+
+impl Default for SpaceInvadersTiming {
+    fn default() -> Self {
+        Self::new(CYCLES_PER_FRAME)
+    }
+}
+
+impl SpaceInvadersTiming {
+    pub fn new(cycles_per_frame: u32) -> Self {
+        Self {
+            cycles_per_frame
+        }
+    }
+
+    //Cycle of the frame when RST 1 has to be requested
+    pub fn mid_frame(&self) -> u32 {
+        self.cycles_per_frame / 2
+    }
+
+    //Cycle of the frame when RST 2 has to be requested
+    pub fn end_frame(&self) -> u32 {
+        self.cycles_per_frame
+    }
+
+    //Returns the RST numbers the host has to request after the cpu went from cycle
+    //`from` to cycle `to` of the current frame
+    pub fn interrupts(&self, from: u32, to: u32) -> Vec<u8> {
+        let mut interrupts = Vec::new();
+        if from < self.mid_frame() && to >= self.mid_frame() {
+            interrupts.push(1);
+        }
+        if from < self.end_frame() && to >= self.end_frame() {
+            interrupts.push(2);
+        }
+        interrupts
+    }
+}
+
+// Space Invaders has a 16 bit shift register used to draw the sprites at any pixel:
+// OUT 4 shifts the value in from the left (the old high byte becomes the low byte)
+// OUT 2 sets the offset, only the 3 lower bits are used
+// IN 3 reads the 8 bits starting offset bits below the top of the register
+// The other ports read 0 and ignore the writes, they are the inputs, the sounds and the watchdog
+// See: http://computerarcheology.com/Arcade/SpaceInvaders/Hardware.html
+#[derive(Default)]
+pub struct ShiftRegister {
+    pub value: u16,
+    pub offset: u8,
+}
+
+impl IoBus for ShiftRegister {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            3 => (self.value >> (8 - self.offset)) as u8,
+            _ => 0x00,
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            2 => self.offset = value & 0x07,
+            4 => self.value = (u16::from(value) << 8) | (self.value >> 8),
+            _ => {},
+        }
+    }
+
+    //The value, low byte first, followed by the offset
+    fn save_state(&self) -> Vec<u8> {
+        vec![(self.value & 0xff) as u8, (self.value >> 8) as u8, self.offset]
+    }
+
+    fn restore_state(&mut self, state: &[u8]) {
+        if let [low, high, offset] = *state {
+            self.value = u16::from(low) | (u16::from(high) << 8);
+            self.offset = offset & 0x07;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupts_of_a_frame() {
+        let timing = SpaceInvadersTiming::default();
+        assert_eq!(timing.cycles_per_frame, 33_333);
+        assert_eq!(timing.mid_frame(), 16_666);
+        assert_eq!(timing.end_frame(), 33_333);
+
+        //a frame run 11 cycles at a time asks for RST 1 and then RST 2, once each
+        let mut requested = Vec::new();
+        let mut cycle = 0;
+        while cycle < CYCLES_PER_FRAME {
+            let next = (cycle + 11).min(CYCLES_PER_FRAME);
+            requested.extend(timing.interrupts(cycle, next));
+            cycle = next;
+        }
+        assert_eq!(requested, [1, 2]);
+
+        assert_eq!(timing.interrupts(0, 16_665), []);
+        assert_eq!(timing.interrupts(16_665, 16_666), [1]);
+        assert_eq!(timing.interrupts(16_666, 33_332), []);
+        assert_eq!(timing.interrupts(33_332, 33_333), [2]);
+        assert_eq!(timing.interrupts(0, 33_333), [1, 2]);
+    }
+
+
+    #[test]
+    fn shift_register() {
+        let mut shift = ShiftRegister::default();
+        shift.output(4, 0xab);
+        assert_eq!(shift.value, 0xab00);
+        shift.output(4, 0xcd);
+        assert_eq!(shift.value, 0xcdab);
+
+        assert_eq!(shift.input(3), 0xcd);
+        shift.output(2, 3);
+        assert_eq!(shift.input(3), 0x6d);
+        //only the 3 lower bits are the offset
+        shift.output(2, 0xff);
+        assert_eq!(shift.offset, 7);
+        assert_eq!(shift.input(3), 0xd5);
+        shift.output(2, 0);
+        assert_eq!(shift.input(3), 0xcd);
+
+        //the other ports read 0 and ignore the writes
+        shift.output(3, 0x12);
+        assert_eq!(shift.input(2), 0x00);
+        assert_eq!(shift.input(4), 0x00);
+        assert_eq!(shift.value, 0xcdab);
+    }
+}
@@ -0,0 +1,29 @@
+// This trait represents the devices connected to the i8080 I/O ports:
+// IN reads a byte from a port into the accumulator and
+// OUT writes the accumulator to a port
+pub trait IoBus {
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+
+    //Returns the state of the devices, used by Cpu::snapshot.
+    //A bus without state saves nothing
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    //Brings the devices back to the state returned by save_state
+    fn restore_state(&mut self, _state: &[u8]) {}
+}
+
+// This struct represents a bus without devices: every port reads 0
+// and the writes are ignored
+#[derive(Default)]
+pub struct NullBus;
+
+impl IoBus for NullBus {
+    fn input(&mut self, _port: u8) -> u8 {
+        0x00
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
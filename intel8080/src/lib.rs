@@ -1,8 +1,20 @@
 mod bit;
+mod flags;
 mod register;
 mod memory;
+mod io;
 mod cpu;
 mod disassembler;
+mod instruction;
+mod invaders;
+#[cfg(test)]
+mod assembler;
 
-pub use cpu::Cpu;
-pub use memory::{Linear, Memory};
+pub use cpu::{Cpu, CpuState, CpmConfig, DebugHit, Event, RunOutcome, SelfTestError, StepTrace, VerboseStep};
+pub use register::{Register, Flags};
+pub use memory::{Linear, BankedMemory, RomRam, RomWriteAttempt, Memory, HexError};
+pub use io::{IoBus, NullBus};
+pub use disassembler::{decode, disassemble_lines, line_spans, disassemble_one, disassemble_backward, subroutine_extent, find_strings, referenced_addresses, DecodedInstruction};
+pub use disassembler::{opcode_info, opcode_table, opcode_is_restricted, OpcodeInfo};
+pub use instruction::{decode_instruction, Instruction, Reg, RegPair, StackPair, Condition};
+pub use invaders::{ShiftRegister, SpaceInvadersTiming, CYCLES_PER_FRAME, VIDEO_RAM};
@@ -1,32 +1,82 @@
+use std::fs;
+use std::io;
+use std::ops::Range;
+#[cfg(feature = "log")]
+use log::{debug};
+
 // This struct represents the intel 8080 memory
 // the processor was able to access to 64KB of memory
 
 pub trait Memory{
     fn get(&self, idx: usize) -> u8;
     fn set(&mut self, idx: usize, value: u8);
+    //Number of addressable bytes
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
+    //The high byte of a word at 0xffff is read from 0x0000, like the 8080 does
     fn get_word(&self, idx: usize) -> u16 {
-        u16::from(self.get(idx)) | (u16::from(self.get(idx + 1)) << 8)
+        u16::from(self.get(idx)) | (u16::from(self.get((idx + 1) & 0xffff)) << 8)
     }
 
     fn set_word(&mut self, idx: usize, value: u16) {
         self.set(idx, (value & 0xff) as u8);
-        self.set(idx + 1, (value >> 8) as u8);
+        self.set((idx + 1) & 0xffff, (value >> 8) as u8);
+    }
+
+    //Returns the content of the memory, used by Cpu::snapshot.
+    //A memory with more bytes than its addresses (e.g. BankedMemory) saves all of them
+    fn save_state(&self) -> Vec<u8> {
+        (0..self.len()).map(|idx| self.get(idx)).collect()
+    }
+
+    //Brings the memory back to the content returned by save_state
+    fn restore_state(&mut self, state: &[u8]) {
+        for (idx, &byte) in state.iter().enumerate() {
+            self.set(idx, byte);
+        }
     }
 }
 
-#[derive(Default)]
+// This enum represents the errors found by Linear::load_hex_string
+#[derive(Debug, Clone, PartialEq)]
+pub enum HexError {
+    //A character that is not an hex digit or a separator
+    InvalidCharacter(char),
+    //An hex digit without the other half of the byte
+    OddDigits,
+    //The bytes don't fit in memory starting from the offset
+    OutOfMemory,
+}
+
+// This struct represents a memory where every address is valid,
+// when it's smaller than 64KB the addresses wrap around its size
+// like on the boards that don't decode all the address lines
 pub struct Linear {
     pub data: Vec<u8>,
 }
 
 impl Memory for Linear {
     fn get(&self, idx: usize) -> u8 {
-        self.data[idx]
+        self.data[idx % self.data.len()]
     }
 
     fn set(&mut self, idx: usize, value: u8) {
-        self.data[idx] = value;
+        let len = self.data.len();
+        self.data[idx % len] = value;
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl Default for Linear {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -36,4 +86,388 @@ impl Linear{
             data: vec![0x00; 0x10000]
         }
     }    
+
+    //Returns a memory of size bytes, e.g. with size 0x400 the address 0x400 is the same as 0x000.
+    //Panics if size is 0
+    pub fn with_size(size: usize) -> Self {
+        assert!(size > 0, "the memory can't be empty");
+        Self{
+            data: vec![0x00; size]
+        }
+    }
+
+    //Returns a 64KB memory with the content of the file at path copied starting from load_addr,
+    //fails if the file can't be read or doesn't fit in memory
+    pub fn from_file(path: &str, load_addr: usize) -> io::Result<Linear> {
+        let rom = fs::read(path)?;
+        let mut memory = Linear::new();
+        if load_addr + rom.len() > memory.data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes loaded at {:04x} don't fit in memory", rom.len(), load_addr),
+            ));
+        }
+
+        memory.data[load_addr..load_addr + rom.len()].copy_from_slice(&rom);
+        Ok(memory)
+    }
+
+    //Sets every byte to 0 keeping the allocated memory
+    pub fn clear(&mut self) {
+        for byte in self.data.iter_mut() {
+            *byte = 0x00;
+        }
+    }
+
+    //Writes the bytes written in hex inside the string starting from offset,
+    //the bytes can be separated by spaces or commas, e.g. "3e 05,06 0380".
+    //Nothing is written if the string is not valid or the bytes don't fit in memory
+    pub fn load_hex_string(&mut self, offset: usize, hex: &str) -> Result<(), HexError> {
+        let mut digits = Vec::new();
+        for c in hex.chars() {
+            match c {
+                ' ' | ',' | '\t' | '\n' | '\r' => {
+                    if digits.len() % 2 != 0 {
+                        return Err(HexError::OddDigits);
+                    }
+                },
+                _ => digits.push(c.to_digit(16).ok_or(HexError::InvalidCharacter(c))? as u8),
+            }
+        }
+        if digits.len() % 2 != 0 {
+            return Err(HexError::OddDigits);
+        }
+        if offset + digits.len() / 2 > self.data.len() {
+            return Err(HexError::OutOfMemory);
+        }
+
+        for (idx, pair) in digits.chunks(2).enumerate() {
+            self.data[offset + idx] = (pair[0] << 4) | pair[1];
+        }
+        Ok(())
+    }
+
+    //Additive checksum of the bytes inside range, used to identify a ROM image
+    pub fn checksum(&self, range: Range<usize>) -> u32 {
+        self.data[range].iter().fold(0u32, |sum, &byte| sum.wrapping_add(u32::from(byte)))
+    }
+
+    //Checksum of a ROM of rom_size bytes loaded at address 0
+    pub fn checksum_rom(&self, rom_size: usize) -> u32 {
+        self.checksum(0..rom_size)
+    }
+}
+
+// This struct represents a memory that extends past 64KB with bank switching:
+// the addresses inside window are mapped to the active bank, the others
+// to a fixed memory. The active bank is usually selected by a device on an OUT
+pub struct BankedMemory {
+    pub fixed: Vec<u8>,
+    pub banks: Vec<Vec<u8>>,
+    pub window: Range<usize>,
+    active: usize,
+}
+
+impl Memory for BankedMemory {
+    fn get(&self, idx: usize) -> u8 {
+        match self.resolve(idx) {
+            (Some(bank), offset) => self.banks[bank][offset],
+            (None, offset) => self.fixed[offset],
+        }
+    }
+
+    fn set(&mut self, idx: usize, value: u8) {
+        match self.resolve(idx) {
+            (Some(bank), offset) => self.banks[bank][offset] = value,
+            (None, offset) => self.fixed[offset] = value,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.fixed.len()
+    }
+
+    //The fixed memory, every bank and the index of the active one
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.fixed.clone();
+        for bank in self.banks.iter() {
+            state.extend_from_slice(bank);
+        }
+        state.extend_from_slice(&(self.active as u64).to_le_bytes());
+        state
+    }
+
+    fn restore_state(&mut self, state: &[u8]) {
+        let (fixed, mut rest) = state.split_at(self.fixed.len());
+        self.fixed.copy_from_slice(fixed);
+        for bank in self.banks.iter_mut() {
+            let (data, next) = rest.split_at(bank.len());
+            bank.copy_from_slice(data);
+            rest = next;
+        }
+        let mut active = [0; 8];
+        active.copy_from_slice(rest);
+        self.select_bank(u64::from_le_bytes(active) as usize);
+    }
+}
+
+impl BankedMemory {
+    //window is the range of addresses switched between bank_count banks, bank 0 is active
+    pub fn new(window: Range<usize>, bank_count: usize) -> Self {
+        Self {
+            fixed: vec![0x00; 0x10000],
+            banks: vec![vec![0x00; window.len()]; bank_count],
+            window,
+            active: 0,
+        }
+    }
+
+    //Maps the window to bank, panics if the bank doesn't exist
+    pub fn select_bank(&mut self, bank: usize) {
+        assert!(bank < self.banks.len(), "bank {} doesn't exist", bank);
+        self.active = bank;
+    }
+
+    pub fn active_bank(&self) -> usize {
+        self.active
+    }
+
+    //Returns where addr is stored: the active bank and the offset inside it for the addresses
+    //in the window, None and the offset inside the fixed memory for the others
+    pub fn resolve(&self, addr: usize) -> (Option<usize>, usize) {
+        let addr = addr & 0xffff;
+        if self.window.contains(&addr) {
+            (Some(self.active), addr - self.window.start)
+        }
+        else {
+            (None, addr)
+        }
+    }
+}
+
+// This struct represents a memory with ROM mapped at the bottom of the address space,
+// e.g. Space Invaders has ROM at 0x0000-0x1fff and RAM above it.
+// The writes below rom_end are ignored like the hardware does, see track_rom_writes to find them
+pub struct RomRam {
+    pub data: Vec<u8>,
+    pub rom_end: usize,
+    rom_writes: Option<Vec<RomWriteAttempt>>,
+}
+
+// This struct represents a write to the ROM of a RomRam, see RomRam::track_rom_writes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RomWriteAttempt {
+    pub addr: u16,
+    pub value: u8,
+}
+
+impl Memory for RomRam {
+    fn get(&self, idx: usize) -> u8 {
+        self.data[idx & 0xffff]
+    }
+
+    fn set(&mut self, idx: usize, value: u8) {
+        let idx = idx & 0xffff;
+        if idx < self.rom_end {
+            #[cfg(feature = "log")]
+            debug!("Write of {:02x} to ROM at {:04x} ignored", value, idx);
+            if let Some(rom_writes) = self.rom_writes.as_mut() {
+                rom_writes.push(RomWriteAttempt { addr: idx as u16, value });
+            }
+        }
+        else {
+            self.data[idx] = value;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl RomRam {
+    //rom is loaded at address 0, the addresses from rom_end are writable RAM.
+    //Panics if rom is longer than 64KB
+    pub fn new(rom: Vec<u8>, rom_end: usize) -> Self {
+        assert!(rom.len() <= 0x10000, "rom of {} bytes doesn't fit in memory", rom.len());
+        let mut data = rom;
+        data.resize(0x10000, 0x00);
+        Self {
+            data,
+            rom_end,
+            rom_writes: None,
+        }
+    }
+
+    //Starts or stops recording the ignored writes to ROM, they aren't recorded by default.
+    //Stopping drops the writes not taken yet
+    pub fn track_rom_writes(&mut self, enabled: bool) {
+        self.rom_writes = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    //Returns the writes to ROM recorded since the last call, oldest first
+    pub fn take_rom_writes(&mut self) -> Vec<RomWriteAttempt> {
+        match self.rom_writes.as_mut() {
+            Some(rom_writes) => std::mem::take(rom_writes),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banked_memory_switches_the_window() {
+        let mut memory = BankedMemory::new(0x8000..0x10000, 2);
+        memory.set(0x0100, 0x11);
+        memory.set(0x8000, 0xaa);
+        memory.select_bank(1);
+        assert_eq!(memory.get(0x8000), 0x00);
+        memory.set(0x8000, 0xbb);
+        assert_eq!(memory.get(0x0100), 0x11);
+
+        memory.select_bank(0);
+        assert_eq!(memory.active_bank(), 0);
+        assert_eq!(memory.get(0x8000), 0xaa);
+        memory.select_bank(1);
+        assert_eq!(memory.get(0x8000), 0xbb);
+    }
+
+
+    #[test]
+    fn load_hex_string() {
+        let mut memory = Linear::new();
+        assert_eq!(memory.load_hex_string(0x10, "3e 05,06 0380 76"), Ok(()));
+        assert_eq!(memory.data[0x10..0x16], [0x3e, 0x05, 0x06, 0x03, 0x80, 0x76]);
+
+        assert_eq!(memory.load_hex_string(0, "3e 0g"), Err(HexError::InvalidCharacter('g')));
+        assert_eq!(memory.load_hex_string(0, "3e 0"), Err(HexError::OddDigits));
+        assert_eq!(memory.load_hex_string(0xffff, "3e 05"), Err(HexError::OutOfMemory));
+        assert_eq!(memory.data[0xffff], 0x00);
+    }
+
+
+    #[test]
+    fn rom_ram_drops_the_writes_to_rom() {
+        let mut memory = RomRam::new(vec![0xc3; 0x2000], 0x2000);
+        memory.set(0x1fff, 0x00);
+        assert_eq!(memory.get(0x1fff), 0xc3);
+
+        memory.set(0x2000, 0x42);
+        assert_eq!(memory.get(0x2000), 0x42);
+        memory.set(0xffff, 0x24);
+        assert_eq!(memory.get(0xffff), 0x24);
+        assert_eq!(memory.get_word(0xffff), 0xc324);
+    }
+
+    #[test]
+    fn rom_ram_records_the_writes_to_rom() {
+        let mut memory = RomRam::new(vec![0xc3; 0x2000], 0x2000);
+        memory.set(0x0000, 0x11);
+        assert!(memory.take_rom_writes().is_empty());
+
+        memory.track_rom_writes(true);
+        memory.set(0x1fff, 0x22);
+        memory.set(0x2000, 0x33);
+        memory.set_word(0x1234, 0xbeef);
+        assert_eq!(
+            memory.take_rom_writes(),
+            [
+                RomWriteAttempt { addr: 0x1fff, value: 0x22 },
+                RomWriteAttempt { addr: 0x1234, value: 0xef },
+                RomWriteAttempt { addr: 0x1235, value: 0xbe },
+            ]
+        );
+        assert!(memory.take_rom_writes().is_empty());
+        assert_eq!(memory.get(0x1fff), 0xc3);
+
+        memory.set(0x0000, 0x44);
+        memory.track_rom_writes(false);
+        assert!(memory.take_rom_writes().is_empty());
+    }
+
+
+    #[test]
+    fn small_linear_memory_wraps() {
+        let mut memory = Linear::with_size(0x400);
+        assert_eq!(memory.len(), 0x400);
+        memory.set(0x400, 0x42);
+        assert_eq!(memory.get(0x000), 0x42);
+        memory.set(0x3ff, 0x24);
+        assert_eq!(memory.get(0xffff), 0x24);
+        assert_eq!(memory.get_word(0x3ff), 0x4224);
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_linear_memory_is_rejected() {
+        Linear::with_size(0);
+    }
+
+    #[test]
+    fn default_linear_memory_is_64kb() {
+        assert_eq!(Linear::default().len(), 0x10000);
+    }
+
+
+    #[test]
+    fn checksum() {
+        let mut memory = Linear::new();
+        memory.load_hex_string(0, "01 02 03 ff").unwrap();
+        assert_eq!(memory.checksum(0..4), 0x0105);
+        assert_eq!(memory.checksum(1..3), 0x0005);
+        assert_eq!(memory.checksum_rom(4), 0x0105);
+        assert_eq!(memory.checksum_rom(0x10000), 0x0105);
+    }
+
+
+    #[test]
+    fn clear_zeroes_every_byte() {
+        let mut memory = Linear::new();
+        memory.load_hex_string(0, "01 02 03").unwrap();
+        memory.set(0x8000, 0x42);
+        memory.set(0xffff, 0xff);
+        memory.clear();
+        assert_eq!(memory.len(), 0x10000);
+        assert!((0..memory.len()).all(|idx| memory.get(idx) == 0x00));
+    }
+
+
+    #[test]
+    fn banked_memory_resolve() {
+        let mut memory = BankedMemory::new(0x8000..0xc000, 3);
+        assert_eq!(memory.resolve(0x0100), (None, 0x0100));
+        assert_eq!(memory.resolve(0xc000), (None, 0xc000));
+        assert_eq!(memory.resolve(0x8000), (Some(0), 0x0000));
+        assert_eq!(memory.resolve(0xbfff), (Some(0), 0x3fff));
+
+        memory.select_bank(2);
+        assert_eq!(memory.resolve(0x8123), (Some(2), 0x0123));
+        assert_eq!(memory.resolve(0x18123), (Some(2), 0x0123));
+        assert_eq!(memory.resolve(0x7fff), (None, 0x7fff));
+    }
+
+
+    #[test]
+    fn from_file() {
+        let path = std::env::temp_dir().join(format!("intel8080-from-file-{}.com", std::process::id()));
+        fs::write(&path, [0x3e, 0x05, 0x76]).unwrap();
+        let path_str = path.to_str().unwrap();
+
+        let memory = Linear::from_file(path_str, 0x0100).unwrap();
+        assert_eq!(memory.len(), 0x10000);
+        assert_eq!(memory.get(0x0100), 0x3e);
+        assert_eq!(memory.get(0x0101), 0x05);
+        assert_eq!(memory.get(0x0102), 0x76);
+        assert_eq!(memory.checksum(0..0x10000), 0x3e + 0x05 + 0x76);
+
+        assert!(Linear::from_file(path_str, 0xfffd).is_ok());
+        let error = Linear::from_file(path_str, 0xfffe).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+        assert!(Linear::from_file(path_str, 0x0100).is_err());
+    }
 }
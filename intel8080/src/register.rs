@@ -1,3 +1,4 @@
+use std::fmt;
 use super::bit;
 
 // This struct represents the i8080's registers
@@ -6,7 +7,8 @@ use super::bit;
 // SP is the stack pointer
 // PC is the program counter
 // See: https://en.wikipedia.org/wiki/Intel_8080#Registers
-#[derive(Default)]
+#[derive(Debug, Default, Clone, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Register {
     pub a: u8,
     pub f: u8,      //Flags
@@ -77,6 +79,7 @@ impl Register {
 // Carry: 1 if the last addition/subtraction had a carry/borrow
 // AC aka Auxiliary Carry: used for binary-coded decimal arithmetic
 // See: https://en.wikipedia.org/wiki/Intel_8080#Flags
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Flags {
     Sign = 7,
     Zero = 6,
@@ -99,5 +102,92 @@ impl Register {
             self.f = bit::clear(self.f, flag as usize)
         }
     }
+
+    //Fixes the bits of F that are not flags: bit 1 is always 1, bits 3 and 5 are always 0
+    pub fn normalize(&mut self) {
+        self.f = self.f & 0xd5 | 0x02;
+    }
+}
+
+// This impl allows to list the registers by name
+impl Register {
+    pub fn values(&self) -> [(&'static str, u16); 10] {
+        [
+            ("A", u16::from(self.a)),
+            ("F", u16::from(self.f)),
+            ("B", u16::from(self.b)),
+            ("C", u16::from(self.c)),
+            ("D", u16::from(self.d)),
+            ("E", u16::from(self.e)),
+            ("H", u16::from(self.h)),
+            ("L", u16::from(self.l)),
+            ("SP", self.sp),
+            ("PC", self.pc),
+        ]
+    }
+}
+
+// This impl prints the registers like the 8080 test logs do, e.g.
+// A:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 F:..0.0.1.
+// the flags are printed as SZ0A0P1C with a '.' for the flags not set,
+// bits 5, 3 and 1 are printed as their value
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags: String = "SZ0A0P1C"
+            .chars()
+            .enumerate()
+            .map(|(idx, name)| {
+                let set = bit::get(self.f, 7 - idx);
+                match name {
+                    '0' | '1' => if set { '1' } else { '0' },
+                    _ => if set { name } else { '.' },
+                }
+            })
+            .collect();
+
+        write!(
+            f,
+            "A:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} F:{}",
+            self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.sp, self.pc, flags
+        )
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        let mut register = Register::new();
+        assert_eq!(
+            register.to_string(),
+            "A:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:0000 PC:0000 F:..0.0.1."
+        );
+
+        register.b = 0x1f;
+        register.set_hl(0xbeef);
+        register.sp = 0x0abc;
+        register.pc = 0x0004;
+        register.set_flag(Flags::Zero, true);
+        register.set_flag(Flags::Parity, true);
+        register.set_flag(Flags::Carry, true);
+        assert_eq!(
+            register.to_string(),
+            "A:00 B:1F C:00 D:00 E:00 H:BE L:EF SP:0ABC PC:0004 F:.Z0.0P1C"
+        );
+    }
+
+
+    #[test]
+    fn normalize_fixes_the_reserved_bits() {
+        let mut register = Register::new();
+        register.f = 0xfd;
+        register.normalize();
+        assert_eq!(register.f, 0xd7);
+
+        register.f = 0x28;
+        register.normalize();
+        assert_eq!(register.f, 0x02);
+    }
+}